@@ -0,0 +1,102 @@
+//! An async front end that runs MDBM calls on Tokio's blocking thread pool.
+
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+
+use tokio::task::{self, JoinHandle};
+
+use {OwnedDatum, MDBM};
+
+/// A handle that may move to another thread.
+///
+/// `MDBM` isn't `Send`, since a handle must not be used from two threads at
+/// once and its stats callback needn't be `Send`. Every handle wrapped here,
+/// the base one included, comes straight from `dup_handle`, so none has a
+/// callback. The base handle is only reached through `AsyncMdbm`'s `Mutex`,
+/// which serializes the `dup_handle` calls made on it, and each duplicate
+/// moves into exactly one blocking task, the only thread that uses it.
+struct SendHandle(MDBM);
+
+unsafe impl Send for SendHandle {}
+
+/// Runs `fetch`, `store` and `delete` on Tokio's blocking thread pool, so
+/// they don't stall the async runtime.
+///
+/// Each call works on its own `dup_handle` of the wrapped database. MDBM's
+/// locks still block: a call waiting on a lock held elsewhere ties up a
+/// blocking-pool thread until the lock is released.
+#[derive(Clone)]
+pub struct AsyncMdbm {
+    base: Arc<Mutex<SendHandle>>,
+}
+
+impl AsyncMdbm {
+    /// Wrap the database `db` is open on.
+    ///
+    /// `db` itself is closed here, along with any stats callback it has,
+    /// and calls run on duplicates of it.
+    pub fn new(db: MDBM) -> Result<AsyncMdbm, io::Error> {
+        let base = db.dup_handle()?;
+        Ok(AsyncMdbm {
+            base: Arc::new(Mutex::new(SendHandle(base))),
+        })
+    }
+
+    /// Fetch a copy of a key's value, or `None` if the key is absent.
+    pub fn fetch(
+        &self,
+        key: Vec<u8>,
+    ) -> impl Future<Output = Result<Option<OwnedDatum>, io::Error>> {
+        self.run(move |db| db.fetch(&key[..]))
+    }
+
+    /// Set a key, as `MDBM::set`.
+    pub fn store(
+        &self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        flags: isize,
+    ) -> impl Future<Output = Result<(), io::Error>> {
+        self.run(move |db| db.set(&key[..], &value[..], flags))
+    }
+
+    /// Delete a key, as `MDBM::delete`.
+    pub fn delete(&self, key: Vec<u8>) -> impl Future<Output = Result<(), io::Error>> {
+        self.run(move |db| db.delete(&key[..]))
+    }
+
+    fn run<T, F>(&self, f: F) -> Blocking<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&MDBM) -> Result<T, io::Error> + Send + 'static,
+    {
+        // A panic while duplicating leaves nothing half-done, so a poisoned
+        // lock is still fine to use.
+        let base = self.base.lock().unwrap_or_else(|e| e.into_inner());
+        let handle = base.0.dup_handle().map(SendHandle);
+        Blocking {
+            handle: task::spawn_blocking(move || f(&handle?.0)),
+        }
+    }
+}
+
+/// The future returned by `AsyncMdbm` calls.
+struct Blocking<T> {
+    handle: JoinHandle<Result<T, io::Error>>,
+}
+
+impl<T> Future for Blocking<T> {
+    type Output = Result<T, io::Error>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        Pin::new(&mut self.handle)
+            .poll(cx)
+            .map(|joined| match joined {
+                Ok(result) => result,
+                Err(e) => Err(io::Error::other(e)),
+            })
+    }
+}
@@ -1,11 +1,143 @@
+#[macro_use]
+extern crate bitflags;
 extern crate libc;
+#[cfg(feature = "serde")]
+extern crate serde;
+#[cfg(feature = "tokio")]
+extern crate tokio;
 
+#[cfg(feature = "bytemuck")]
+use std::borrow::Cow;
+use std::cell::Cell;
+use std::collections::btree_map::{self, BTreeMap};
+use std::collections::hash_map::RandomState;
+use std::collections::HashSet;
+use std::convert::TryFrom;
+use std::env;
+use std::error;
+use std::ffi::CStr;
+use std::fmt;
+use std::fs;
+use std::hash::{BuildHasher, Hasher};
 use std::io;
 use std::mem;
+use std::ops::{Deref, DerefMut};
 use std::os::unix::ffi::OsStringExt;
+use std::os::unix::io::AsRawFd;
+use std::panic;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::ptr;
 use std::slice;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-#[derive(Copy, Clone)]
+// Emits a `tracing` event when the `tracing` feature is enabled, and nothing
+// otherwise. Only sizes and return codes are recorded, never key or value
+// contents.
+#[cfg(feature = "tracing")]
+macro_rules! trace_op {
+    ($($arg:tt)*) => {
+        tracing::debug!($($arg)*)
+    };
+}
+
+#[cfg(not(feature = "tracing"))]
+macro_rules! trace_op {
+    ($($arg:tt)*) => {};
+}
+
+#[cfg(feature = "tokio")]
+mod async_mdbm;
+
+#[cfg(feature = "tokio")]
+pub use async_mdbm::AsyncMdbm;
+
+/// Context for a failed MDBM call: which operation failed, and the errno it
+/// left behind.
+///
+/// Errors from this crate are `io::Error`s wrapping an `MdbmError`, so
+/// `kind()` still works as usual; use `MdbmError::from_io` to get at the
+/// details.
+#[derive(Debug)]
+pub struct MdbmError {
+    operation: &'static str,
+    errno: i32,
+    source: io::Error,
+}
+
+impl MdbmError {
+    /// Capture the current `errno` as the result of `operation`.
+    fn last(operation: &'static str) -> MdbmError {
+        let source = io::Error::last_os_error();
+        MdbmError {
+            operation,
+            errno: source.raw_os_error().unwrap_or(0),
+            source,
+        }
+    }
+
+    fn from_errno(operation: &'static str, errno: i32) -> MdbmError {
+        MdbmError {
+            operation,
+            errno,
+            source: io::Error::from_raw_os_error(errno),
+        }
+    }
+
+    /// Get the `MdbmError` inside an error returned by this crate, if any.
+    pub fn from_io(err: &io::Error) -> Option<&MdbmError> {
+        err.get_ref().and_then(|e| e.downcast_ref::<MdbmError>())
+    }
+
+    /// The MDBM operation that failed, e.g. `"store"` or `"fetch"`.
+    pub fn operation(&self) -> &'static str {
+        self.operation
+    }
+
+    /// The raw OS error number.
+    pub fn errno(&self) -> i32 {
+        self.errno
+    }
+}
+
+impl fmt::Display for MdbmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // io::Error appends its own " (os error N)"; we print errno ourselves.
+        let msg = self.source.to_string();
+        let suffix = format!(" (os error {})", self.errno);
+        write!(
+            f,
+            "mdbm {} failed: {} (errno {})",
+            self.operation,
+            msg.trim_end_matches(suffix.as_str()),
+            self.errno
+        )
+    }
+}
+
+impl error::Error for MdbmError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        Some(&self.source)
+    }
+}
+
+impl From<MdbmError> for io::Error {
+    fn from(err: MdbmError) -> io::Error {
+        io::Error::new(err.source.kind(), err)
+    }
+}
+
+/// Build an error for a failed `operation` from the current `errno`.
+fn last_error(operation: &'static str) -> io::Error {
+    MdbmError::last(operation).into()
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
 pub enum ReadState {
     ReadOnly,
     WriteOnly,
@@ -22,7 +154,10 @@ impl ReadState {
     }
 }
 
-#[derive(Copy, Clone)]
+/// With the `serde` feature, hashes are named in lowercase, e.g. `"fnv"`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "lowercase"))]
 pub enum HashFunction {
     CRC32,
     EJB,
@@ -41,6 +176,21 @@ pub enum HashFunction {
 }
 
 impl HashFunction {
+    /// Every distinct hash; `MAX` is left out since it aliases `HSIEH`.
+    const ALL: [HashFunction; 11] = [
+        HashFunction::CRC32,
+        HashFunction::EJB,
+        HashFunction::FNV,
+        HashFunction::HSIEH,
+        HashFunction::JENKINS,
+        HashFunction::MD5,
+        HashFunction::OZ,
+        HashFunction::PHONG,
+        HashFunction::SHA1,
+        HashFunction::STL,
+        HashFunction::TOREK,
+    ];
+
     fn hash_constant(&self) -> u32 {
         match self {
             HashFunction::CRC32 => mdbm_sys::MDBM_HASH_CRC32,
@@ -57,18 +207,408 @@ impl HashFunction {
             HashFunction::TOREK => mdbm_sys::MDBM_HASH_TOREK,
         }
     }
+
+    fn from_constant(c: u32) -> Option<HashFunction> {
+        // MDBM_HASH_MAX aliases the highest-numbered hash, so it is never
+        // returned here.
+        let h = match c {
+            mdbm_sys::MDBM_HASH_CRC32 => HashFunction::CRC32,
+            mdbm_sys::MDBM_HASH_EJB => HashFunction::EJB,
+            mdbm_sys::MDBM_HASH_FNV => HashFunction::FNV,
+            mdbm_sys::MDBM_HASH_HSIEH => HashFunction::HSIEH,
+            mdbm_sys::MDBM_HASH_JENKINS => HashFunction::JENKINS,
+            mdbm_sys::MDBM_HASH_MD5 => HashFunction::MD5,
+            mdbm_sys::MDBM_HASH_OZ => HashFunction::OZ,
+            mdbm_sys::MDBM_HASH_PHONG => HashFunction::PHONG,
+            mdbm_sys::MDBM_HASH_SHA_1 => HashFunction::SHA1,
+            mdbm_sys::MDBM_HASH_STL => HashFunction::STL,
+            mdbm_sys::MDBM_HASH_TOREK => HashFunction::TOREK,
+            _ => return None,
+        };
+        Some(h)
+    }
+}
+
+/// Alignment of values within a page.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Alignment {
+    Bits8,
+    Bits16,
+    Bits32,
+    Bits64,
+}
+
+impl Alignment {
+    fn mask(&self) -> u32 {
+        match self {
+            Alignment::Bits8 => mdbm_sys::MDBM_ALIGN_8_BITS,
+            Alignment::Bits16 => mdbm_sys::MDBM_ALIGN_16_BITS,
+            Alignment::Bits32 => mdbm_sys::MDBM_ALIGN_32_BITS,
+            Alignment::Bits64 => mdbm_sys::MDBM_ALIGN_64_BITS,
+        }
+    }
+
+    fn from_mask(mask: u32) -> Option<Alignment> {
+        let a = match mask {
+            mdbm_sys::MDBM_ALIGN_8_BITS => Alignment::Bits8,
+            mdbm_sys::MDBM_ALIGN_16_BITS => Alignment::Bits16,
+            mdbm_sys::MDBM_ALIGN_32_BITS => Alignment::Bits32,
+            mdbm_sys::MDBM_ALIGN_64_BITS => Alignment::Bits64,
+            _ => return None,
+        };
+        Some(a)
+    }
+}
+
+/// Per-operation counters kept by MDBM once stat operations are enabled.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum StatCounter {
+    Fetch,
+    Store,
+    Delete,
+}
+
+impl StatCounter {
+    fn stat_type(&self) -> mdbm_sys::mdbm_stat_type {
+        match self {
+            StatCounter::Fetch => mdbm_sys::mdbm_stat_type_MDBM_STAT_TYPE_FETCH,
+            StatCounter::Store => mdbm_sys::mdbm_stat_type_MDBM_STAT_TYPE_STORE,
+            StatCounter::Delete => mdbm_sys::mdbm_stat_type_MDBM_STAT_TYPE_DELETE,
+        }
+    }
+}
+
+/// How a database used as a cache picks entries to evict when full.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum CacheMode {
+    /// Least frequently used.
+    Lfu,
+    /// Least recently used.
+    Lru,
+    /// Greedy-dual-size-frequency, which favors evicting large, rarely used
+    /// entries.
+    Gdsf,
+}
+
+impl CacheMode {
+    fn mode(&self) -> u32 {
+        match self {
+            CacheMode::Lfu => mdbm_sys::MDBM_CACHEMODE_LFU,
+            CacheMode::Lru => mdbm_sys::MDBM_CACHEMODE_LRU,
+            CacheMode::Gdsf => mdbm_sys::MDBM_CACHEMODE_GDSF,
+        }
+    }
+
+    fn from_mode(mode: u32) -> Option<CacheMode> {
+        match mode {
+            mdbm_sys::MDBM_CACHEMODE_LFU => Some(CacheMode::Lfu),
+            mdbm_sys::MDBM_CACHEMODE_LRU => Some(CacheMode::Lru),
+            mdbm_sys::MDBM_CACHEMODE_GDSF => Some(CacheMode::Gdsf),
+            _ => None,
+        }
+    }
+}
+
+/// The kind of event reported to a stats callback.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum StatType {
+    Fetch,
+    Store,
+    Delete,
+    Lock,
+    CacheEvict,
+    Sync,
+    /// Any other `MDBM_STAT_TAG_*` value.
+    Other(i32),
+}
+
+impl StatType {
+    fn from_tag(tag: libc::c_int) -> StatType {
+        match tag as u32 {
+            mdbm_sys::MDBM_STAT_TAG_FETCH => StatType::Fetch,
+            mdbm_sys::MDBM_STAT_TAG_STORE => StatType::Store,
+            mdbm_sys::MDBM_STAT_TAG_DELETE => StatType::Delete,
+            mdbm_sys::MDBM_STAT_TAG_LOCK => StatType::Lock,
+            mdbm_sys::MDBM_STAT_TAG_CACHE_EVICT => StatType::CacheEvict,
+            mdbm_sys::MDBM_STAT_TAG_SYNC => StatType::Sync,
+            _ => StatType::Other(tag),
+        }
+    }
+}
+
+type StatsCallback = Box<dyn FnMut(StatType, u64)>;
+
+unsafe extern "C" fn stats_trampoline(
+    _db: *mut mdbm_sys::MDBM,
+    tag: libc::c_int,
+    _flags: libc::c_int,
+    value: u64,
+    user: *mut libc::c_void,
+) {
+    let callback = &mut *(user as *mut StatsCallback);
+    // Unwinding into C is undefined behavior, so a panicking callback only
+    // loses this one event.
+    let _ = panic::catch_unwind(panic::AssertUnwindSafe(|| {
+        callback(StatType::from_tag(tag), value)
+    }));
+}
+
+/// How keys and values are written by `MDBM::dump_to`.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum DumpEncoding {
+    /// Raw text, with invalid UTF-8 replaced. Easiest to read, but lossy, and
+    /// data containing tabs or newlines breaks the line format.
+    Utf8Lossy,
+    /// Standard base64 with padding.
+    Base64,
+    /// Lowercase hexadecimal.
+    Hex,
+}
+
+const BASE64_ALPHABET: &[u8; 64] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+impl DumpEncoding {
+    fn encode(self, bytes: &[u8], out: &mut String) {
+        match self {
+            DumpEncoding::Utf8Lossy => out.push_str(&String::from_utf8_lossy(bytes)),
+            DumpEncoding::Base64 => {
+                for chunk in bytes.chunks(3) {
+                    let b = [
+                        chunk[0],
+                        *chunk.get(1).unwrap_or(&0),
+                        *chunk.get(2).unwrap_or(&0),
+                    ];
+                    let n = (b[0] as usize) << 16 | (b[1] as usize) << 8 | b[2] as usize;
+                    for i in 0..4 {
+                        if i <= chunk.len() {
+                            out.push(BASE64_ALPHABET[(n >> (18 - 6 * i)) & 0x3f] as char);
+                        } else {
+                            out.push('=');
+                        }
+                    }
+                }
+            }
+            DumpEncoding::Hex => {
+                for b in bytes {
+                    out.push_str(&format!("{:02x}", b));
+                }
+            }
+        }
+    }
+
+    fn decode(self, text: &str) -> Result<Vec<u8>, String> {
+        match self {
+            DumpEncoding::Utf8Lossy => Ok(text.as_bytes().to_vec()),
+            DumpEncoding::Base64 => {
+                let mut out = Vec::with_capacity(text.len() / 4 * 3);
+                let (mut acc, mut bits) = (0u32, 0);
+                for c in text.trim_end_matches('=').bytes() {
+                    let v = match BASE64_ALPHABET.iter().position(|&a| a == c) {
+                        Some(v) => v as u32,
+                        None => return Err(format!("invalid base64 character {:?}", c as char)),
+                    };
+                    acc = acc << 6 | v;
+                    bits += 6;
+                    if bits >= 8 {
+                        bits -= 8;
+                        out.push((acc >> bits) as u8);
+                        acc &= (1 << bits) - 1;
+                    }
+                }
+                Ok(out)
+            }
+            DumpEncoding::Hex => {
+                if text.len() % 2 == 1 {
+                    return Err("odd number of hex digits".to_string());
+                }
+                text.as_bytes()
+                    .chunks(2)
+                    .map(|pair| {
+                        match (
+                            (pair[0] as char).to_digit(16),
+                            (pair[1] as char).to_digit(16),
+                        ) {
+                            (Some(hi), Some(lo)) => Ok((hi << 4 | lo) as u8),
+                            _ => Err(format!(
+                                "invalid hex digits {:?}",
+                                String::from_utf8_lossy(pair)
+                            )),
+                        }
+                    })
+                    .collect()
+            }
+        }
+    }
+}
+
+/// What a store does when the key already exists.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum StoreMode {
+    /// Keep the existing value (`MDBM_INSERT`).
+    Insert,
+    /// Overwrite the existing value (`MDBM_REPLACE`).
+    Replace,
+    /// Add another value under the same key (`MDBM_INSERT_DUP`).
+    InsertDuplicate,
+    /// Only store if the key already exists (`MDBM_MODIFY`).
+    Modify,
+}
+
+impl StoreMode {
+    fn flag(self) -> isize {
+        (match self {
+            StoreMode::Insert => mdbm_sys::MDBM_INSERT,
+            StoreMode::Replace => mdbm_sys::MDBM_REPLACE,
+            StoreMode::InsertDuplicate => mdbm_sys::MDBM_INSERT_DUP,
+            StoreMode::Modify => mdbm_sys::MDBM_MODIFY,
+        }) as isize
+    }
+}
+
+bitflags! {
+    /// Modifiers for `MDBM::store_flags`, on top of its `StoreMode`.
+    ///
+    /// The store modes share MDBM's low two flag bits, so they can't be
+    /// combined and stay a `StoreMode`; these are the bits that can.
+    pub struct StoreFlags: u32 {
+        /// Reserve space without copying a value in. `store_flags` rejects
+        /// it; use `reserve_value` instead.
+        const RESERVE = mdbm_sys::MDBM_RESERVE;
+        /// Mark the entry clean in a cache with a backing store.
+        const CLEAN = mdbm_sys::MDBM_CLEAN;
+        /// Store only in the cache, not the backing store.
+        const CACHE_ONLY = mdbm_sys::MDBM_CACHE_ONLY;
+        /// Update the cache only if the key is already cached. Without it,
+        /// the cache is updated either way (`MDBM_CACHE_REPLACE`).
+        const CACHE_MODIFY = mdbm_sys::MDBM_CACHE_MODIFY;
+    }
+}
+
+/// Compresses values for `MDBM::store_compressed` and
+/// `MDBM::fetch_decompressed`.
+pub trait Compressor {
+    fn compress(&self, data: &[u8]) -> Vec<u8>;
+    /// Undo `compress`, or fail with `InvalidData` on corrupt input.
+    fn decompress(&self, data: &[u8]) -> Result<Vec<u8>, io::Error>;
+}
+
+/// Header byte for a value `store_compressed` kept as is.
+const STORED_RAW: u8 = 0;
+/// Header byte for a value `store_compressed` compressed.
+const STORED_COMPRESSED: u8 = 1;
+
+/// How `MDBM::merge` handles a key present in both databases.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ConflictPolicy {
+    /// Keep this database's value.
+    KeepExisting,
+    /// Take the other database's value.
+    Overwrite,
+    /// Stop the merge with an `AlreadyExists` error.
+    Error,
+}
+
+/// Key-level differences between two databases, from `MDBM::diff`.
+///
+/// Each list is sorted by key.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct DbDiff {
+    pub only_in_self: Vec<Vec<u8>>,
+    pub only_in_other: Vec<Vec<u8>>,
+    /// Keys in both databases with different values.
+    pub value_mismatch: Vec<Vec<u8>>,
+}
+
+/// How a windowed database's window is being used, from
+/// `MDBM::window_stats`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct WindowStats {
+    /// Page accesses served by pages already in the window.
+    pub reused: u64,
+    /// Page accesses that had to map a page into the window.
+    pub remapped: u64,
+    /// The window size in bytes.
+    pub window_size: u32,
+    /// The most of the window ever in use, in bytes.
+    pub max_window_used: u32,
+}
+
+/// The policy set by `MDBM::set_window_auto_grow`, with the window stats
+/// as of its last check.
+#[derive(Copy, Clone)]
+struct WindowGrowth {
+    size: usize,
+    max: usize,
+    factor: f64,
+    reused: u64,
+    remapped: u64,
+}
+
+/// Window accesses between checks for thrashing, so a handful of reads
+/// doesn't trigger growth.
+const WINDOW_CHECK_INTERVAL: u64 = 64;
+
+/// The effective settings of an open database, from `MDBM::config`.
+#[derive(Copy, Clone, Debug)]
+pub struct DbConfig {
+    pub page_size: u32,
+    /// `None` if the file uses a hash this crate doesn't know.
+    pub hash: Option<HashFunction>,
+    pub alignment: Option<Alignment>,
+    /// Whether this handle was opened with `large_objects`.
+    pub large_objects: bool,
+    /// `None` unless the database is used as a cache.
+    pub cache_mode: Option<CacheMode>,
+    /// The size limit in bytes, or `None` if the database may grow freely.
+    pub limit_size: Option<u64>,
 }
 
+/// How much memory a database's mapping takes, from `MDBM::memory_usage`.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct MemUsage {
+    /// Size of the mapped database.
+    pub mapped_bytes: u64,
+    /// Bytes of the mapping currently in RAM.
+    pub resident_bytes: u64,
+}
+
+/// With the `serde` feature, fields missing when deserializing take their
+/// `Default` values.
 #[derive(Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
 pub struct Options {
     pub reads: ReadState,
     pub create: bool,
     pub hash: Option<HashFunction>,
+    /// Allow values larger than a page (`MDBM_LARGE_OBJECTS`).
+    pub large_objects: bool,
+    /// Use reader-writer locks (`MDBM_RW_LOCKS`), so that several processes
+    /// can read the file at once while writers still get it to themselves.
+    ///
+    /// MDBM always maps the file shared, so every process sees the others'
+    /// writes either way. The lock mode is recorded in the file, and MDBM
+    /// refuses opens that ask for a different one, so all processes sharing
+    /// a file must agree on this setting.
+    pub inter_process: bool,
+    /// Initial size of the database in bytes, used instead of `new`'s
+    /// `presize`.
+    ///
+    /// Rounded up to a whole number of pages of the page size being opened
+    /// with, so the database starts out at least this large.
+    pub presize_bytes: Option<u64>,
 }
 
 impl<'a> Into<u32> for Options {
     fn into(self) -> u32 {
-        let f = self.reads.flag();
+        let mut f = self.reads.flag();
+        if self.large_objects {
+            f |= mdbm_sys::MDBM_LARGE_OBJECTS;
+        }
+        if self.inter_process {
+            f |= mdbm_sys::MDBM_RW_LOCKS;
+        }
         if !self.create {
             return f;
         }
@@ -83,17 +623,111 @@ impl Default for Options {
             reads: ReadState::ReadWrite,
             create: true,
             hash: None,
+            large_objects: false,
+            inter_process: false,
+            presize_bytes: None,
         }
     }
 }
 
+/// Named settings for opening a database, created by `MDBM::open`.
+///
+/// Anything left unset matches `MDBM::new` with `Options::default()`, mode
+/// `0o644`, and MDBM's default page and initial sizes.
+pub struct Builder {
+    path: PathBuf,
+    options: Options,
+    mode: u32,
+    page_size: u32,
+    presize: u32,
+}
+
+impl Builder {
+    /// Open for reading only.
+    pub fn read_only(mut self) -> Builder {
+        self.options.reads = ReadState::ReadOnly;
+        self
+    }
+
+    /// Open for reading and writing. This is the default.
+    pub fn read_write(mut self) -> Builder {
+        self.options.reads = ReadState::ReadWrite;
+        self
+    }
+
+    /// Whether to create the file if it doesn't exist. Defaults to `true`.
+    pub fn create(mut self, create: bool) -> Builder {
+        self.options.create = create;
+        self
+    }
+
+    /// The hash function; see `MDBM::new` for how this interacts with an
+    /// existing file.
+    pub fn hash(mut self, hash: HashFunction) -> Builder {
+        self.options.hash = Some(hash);
+        self
+    }
+
+    /// Whether to use locks suited to sharing the file between processes;
+    /// see `Options::inter_process`.
+    pub fn inter_process(mut self, inter_process: bool) -> Builder {
+        self.options.inter_process = inter_process;
+        self
+    }
+
+    /// Permission bits for a newly created file.
+    pub fn mode(mut self, mode: u32) -> Builder {
+        self.mode = mode;
+        self
+    }
+
+    /// Page size in bytes; 0 uses MDBM's default.
+    pub fn page_size(mut self, page_size: u32) -> Builder {
+        self.page_size = page_size;
+        self
+    }
+
+    /// Initial size of a new database in bytes; 0 uses MDBM's default.
+    pub fn presize(mut self, presize: u32) -> Builder {
+        self.presize = presize;
+        self
+    }
+
+    /// Open the database.
+    pub fn finish(self) -> Result<MDBM, io::Error> {
+        MDBM::new(
+            self.path,
+            self.options,
+            self.mode as usize,
+            self.page_size as usize,
+            self.presize as usize,
+        )
+    }
+}
+
 pub struct MDBM {
     db: *mut mdbm_sys::MDBM,
+    path: PathBuf,
+    large_objects: bool,
+    default_store_mode: StoreMode,
+    // Boxed twice so MDBM can hold a thin pointer to it. Fields drop after
+    // `Drop::drop` closes the handle, so MDBM never calls a freed callback.
+    stats_callback: Option<Box<StatsCallback>>,
+    window_growth: Cell<Option<WindowGrowth>>,
 }
 
 impl MDBM {
     /// Open a database.
     ///
+    /// If `options.hash` is set and the file already exists, the file's hash
+    /// must match it; MDBM would otherwise silently keep the file's hash, so a
+    /// mismatch is reported as an `InvalidInput` error instead.
+    ///
+    /// `psize` is either 0 for MDBM's default page size, or a power of two
+    /// between `MDBM_MINPAGE` and `MDBM_MAXPAGE`; anything else is rejected
+    /// with `InvalidInput` before the file is touched.
+    ///
+    /// `presize` is ignored when `options.presize_bytes` is set.
     pub fn new<P: Into<std::path::PathBuf>>(
         path: P,
         options: Options,
@@ -101,314 +735,6006 @@ impl MDBM {
         psize: usize,
         presize: usize,
     ) -> Result<MDBM, io::Error> {
+        MDBM::open_with_flags(path, options, 0, mode, psize, presize)
+    }
+
+    /// Open a database in windowed mode (`MDBM_OPEN_WINDOWED`), mapping at
+    /// most `window_size` bytes of the file at a time, as with `new`.
+    ///
+    /// # Safety
+    ///
+    /// Any call on a windowed handle may remap the window, so bytes borrowed
+    /// from the database, such as through `Lock::get`, must not be held
+    /// across another call on the handle.
+    pub unsafe fn open_windowed<P: Into<std::path::PathBuf>>(
+        path: P,
+        options: Options,
+        mode: usize,
+        psize: usize,
+        presize: usize,
+        window_size: usize,
+    ) -> Result<MDBM, io::Error> {
+        let db = MDBM::open_with_flags(
+            path,
+            options,
+            mdbm_sys::MDBM_OPEN_WINDOWED,
+            mode,
+            psize,
+            presize,
+        )?;
+        db.set_window_size(window_size)?;
+        Ok(db)
+    }
+
+    fn open_with_flags<P: Into<std::path::PathBuf>>(
+        path: P,
+        options: Options,
+        extra_flags: u32,
+        mode: usize,
+        psize: usize,
+        presize: usize,
+    ) -> Result<MDBM, io::Error> {
+        check_page_size(psize)?;
+        let presize = match options.presize_bytes {
+            Some(bytes) => presize_for_bytes(bytes, psize)?,
+            None => presize,
+        };
+
         // Rust Path objects are not null-terminated.
         // To null-terminate it, we need to:
 
         // 1. Take ownership of it, so we can modify the underlying buf.
         //   - This may or may not copy, depending on what was passed in.
         let path_buf = path.into();
+        let existed = path_buf.exists();
         // 2. Treat the string as a Unix string (i.e. assume Unix utf8 encoding)
-        //   - This should be a no-op
-        let path_bytes = path_buf.into_os_string();
+        //   - This copies, since the handle keeps the original for `path()`
+        let path_bytes = path_buf.clone().into_os_string();
         // 3. Treat it as a vector of bytes
         //   - This should be a no-op
         let path_vec: Vec<u8> = path_bytes.into_vec();
         // 4. Append a null byte
         let path_cstring = std::ffi::CString::new(path_vec)?;
 
-        let flag_u32: u32 = options.into();
+        let flag_u32: u32 = Into::<u32>::into(options) | extra_flags;
 
         unsafe {
+            // mdbm_open copies the path, so it only has to outlive the call.
             let db = mdbm_sys::mdbm_open(
-                path_cstring.into_raw(),
+                path_cstring.as_ptr(),
                 flag_u32 as libc::c_int,
                 mode as libc::c_int,
                 psize as libc::c_int,
                 presize as libc::c_int,
             );
+            trace_op!(op = "open", flags = flag_u32, ok = !db.is_null());
 
             if db.is_null() {
-                return Err(io::Error::last_os_error());
+                return Err(last_error("open"));
             }
+            let db = MDBM {
+                db,
+                path: path_buf,
+                large_objects: options.large_objects,
+                default_store_mode: StoreMode::Replace,
+                stats_callback: None,
+                window_growth: Cell::new(None),
+            };
             match options.hash {
                 None => {}
-                Some(h) => {
-                    mdbm_sys::mdbm_set_hash(db, h.hash_constant() as libc::c_int);
+                Some(h) if existed => {
+                    let actual = mdbm_sys::mdbm_get_hash(db.db) as u32;
+                    if actual != h.hash_constant() {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidInput,
+                            format!(
+                                "requested hash {:?}, but the existing file uses {:?}",
+                                h,
+                                db.get_hash()
+                            ),
+                        ));
+                    }
                 }
+                Some(h) => db.set_hash(h)?,
             };
-            Ok(MDBM { db: db })
+            Ok(db)
         }
     }
 
-    /// Set a key.
-    pub fn set<'k, 'v, K, V>(&self, key: &'k K, value: &'v V, flags: isize) -> Result<(), io::Error>
-    where
-        K: AsDatum<'k> + ?Sized,
-        V: AsDatum<'v> + ?Sized,
-    {
-        unsafe {
-            let rc = mdbm_sys::mdbm_store(
-                self.db,
-                to_raw_datum(&key.as_datum()),
-                to_raw_datum(&value.as_datum()),
-                flags as libc::c_int,
-            );
-
-            if rc == -1 {
-                Err(io::Error::last_os_error())
-            } else {
-                Ok(())
-            }
+    /// Start building the settings to open the database at `path`.
+    pub fn open<P: Into<PathBuf>>(path: P) -> Builder {
+        Builder {
+            path: path.into(),
+            options: Default::default(),
+            mode: 0o644,
+            page_size: 0,
+            presize: 0,
         }
     }
 
-    /// Lock a key.
-    pub fn lock<'a, K>(&'a self, key: &'a K, flags: isize) -> Result<Lock<'a>, io::Error>
-    where
-        K: AsDatum<'a> + ?Sized,
-    {
-        let rc = unsafe {
-            mdbm_sys::mdbm_lock_smart(
-                self.db,
-                &to_raw_datum(&key.as_datum()),
-                flags as libc::c_int,
-            )
-        };
-
-        if rc == 1 {
-            Ok(Lock {
-                db: self,
-                key: key.as_datum(),
+    /// Score how well each hash function spreads a sample of keys, best
+    /// first.
+    ///
+    /// Each key is hashed with `mdbm_hash`, without opening a database, into
+    /// as many buckets as the next power of two above the key count. The
+    /// score is the share of keys landing in an already-used bucket, so lower
+    /// is better; a perfect spread scores 0.
+    pub fn evaluate_hashes(keys: &[&[u8]]) -> Vec<(HashFunction, f64)> {
+        let buckets = keys.len().next_power_of_two();
+        let mut scores: Vec<(HashFunction, f64)> = HashFunction::ALL
+            .iter()
+            .map(|&hash| {
+                let mut used = vec![false; buckets];
+                let mut collisions = 0;
+                for key in keys {
+                    let h = unsafe {
+                        mdbm_sys::mdbm_hash(
+                            key.as_ptr() as *mut libc::c_uchar,
+                            key.len() as libc::c_int,
+                            hash.hash_constant() as libc::c_int,
+                        )
+                    };
+                    let bucket = h as usize & (buckets - 1);
+                    if used[bucket] {
+                        collisions += 1;
+                    }
+                    used[bucket] = true;
+                }
+                let score = if keys.is_empty() {
+                    0.0
+                } else {
+                    collisions as f64 / keys.len() as f64
+                };
+                (hash, score)
             })
-        } else {
-            Err(io::Error::last_os_error())
-        }
+            .collect();
+        scores.sort_by(|a, b| a.1.total_cmp(&b.1));
+        scores
     }
-}
 
-impl Drop for MDBM {
-    fn drop(&mut self) {
-        unsafe {
-            mdbm_sys::mdbm_sync(self.db);
-            mdbm_sys::mdbm_close(self.db);
+    /// Open a database, retrying transient failures.
+    ///
+    /// Opens that fail with `EAGAIN`, `EINTR` or `EBUSY` (for example while
+    /// another process is replacing the file) are retried up to `retries`
+    /// times, sleeping `backoff` before the first retry and doubling it each
+    /// time after. Any other error is returned immediately, as is the last
+    /// error once retries run out.
+    pub fn open_with_retry<P: Into<PathBuf>>(
+        path: P,
+        options: Options,
+        mode: usize,
+        psize: usize,
+        presize: usize,
+        retries: u32,
+        backoff: Duration,
+    ) -> Result<MDBM, io::Error> {
+        let path = path.into();
+        retry_transient(retries, backoff, || {
+            MDBM::new(path.clone(), options, mode, psize, presize)
+        })
+    }
+
+    /// Open a database that may not exist.
+    ///
+    /// Like `new`, but returns `Ok(None)` when `options.create` is false and
+    /// there is no file at `path`. Every other failure is still an error.
+    pub fn try_open<P: Into<PathBuf>>(
+        path: P,
+        options: Options,
+        mode: usize,
+        psize: usize,
+        presize: usize,
+    ) -> Result<Option<MDBM>, io::Error> {
+        match MDBM::new(path, options, mode, psize, presize) {
+            Ok(db) => Ok(Some(db)),
+            Err(ref e) if !options.create && e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
         }
     }
-}
 
-pub struct Datum<'a> {
-    bytes: &'a [u8],
-}
+    /// Whether `path` holds a usable MDBM file.
+    ///
+    /// The file is opened read-only, never created, and closed again; MDBM
+    /// checks the header's magic number while opening. Any failure, including
+    /// a missing file, gives `false`.
+    pub fn is_mdbm_file<P: Into<PathBuf>>(path: P) -> bool {
+        let options = Options {
+            reads: ReadState::ReadOnly,
+            create: false,
+            ..Default::default()
+        };
+        match MDBM::new(path, options, 0o644, 0, 0) {
+            Ok(db) => unsafe { mdbm_sys::mdbm_get_version(db.db) != 0 },
+            Err(_) => false,
+        }
+    }
 
-impl<'a> Datum<'a> {
-    pub fn new(bytes: &'a [u8]) -> Datum<'a> {
-        Datum { bytes: bytes }
+    /// Open a scratch database that leaves nothing behind on disk.
+    ///
+    /// MDBM has no anonymous mode, so this creates a file in the temporary
+    /// directory and unlinks it right after opening; the mapping keeps the
+    /// data alive until the handle is closed.
+    pub fn in_memory(options: Options) -> Result<MDBM, io::Error> {
+        MDBM::open_unlinked(temp_db_path(), options)
     }
-}
 
-pub trait AsDatum<'a> {
-    fn as_datum(&'a self) -> Datum<'a>;
-}
+    /// Open a throwaway, writable copy of the database at `source`.
+    ///
+    /// The source is opened read-only and copied under its database lock to
+    /// a file in the temporary directory, which is then opened and unlinked
+    /// like `in_memory`. Changes to the copy never reach `source`, and the
+    /// copy disappears when the handle closes.
+    pub fn open_copy<P: Into<PathBuf>>(source: P) -> Result<MDBM, io::Error> {
+        let source_options = Options {
+            reads: ReadState::ReadOnly,
+            create: false,
+            ..Default::default()
+        };
+        let source = MDBM::new(source, source_options, 0o644, 0, 0)?;
+        let path = temp_db_path();
+        if let Err(e) = source.copy_to(&path) {
+            let _ = fs::remove_file(&path);
+            return Err(e);
+        }
+        MDBM::open_unlinked(path, Default::default())
+    }
 
-impl<'a, T: AsDatum<'a> + ?Sized> AsDatum<'a> for &'a T {
-    fn as_datum(&'a self) -> Datum<'a> {
-        (**self).as_datum()
+    fn open_unlinked(path: PathBuf, options: Options) -> Result<MDBM, io::Error> {
+        let options = Options {
+            create: true,
+            ..options
+        };
+        let db = MDBM::new(path.clone(), options, 0o600, 0, 0)?;
+        fs::remove_file(&path)?;
+        Ok(db)
     }
-}
 
-impl<'a> AsDatum<'a> for [u8] {
-    fn as_datum(&'a self) -> Datum<'a> {
-        Datum::new(self)
+    /// Set the hash function.
+    ///
+    /// Changing the hash of a populated database would strand its entries,
+    /// so this fails with `InvalidInput` unless the database is empty.
+    pub fn set_hash(&self, hash: HashFunction) -> Result<(), io::Error> {
+        if unsafe { mdbm_sys::mdbm_count_records(self.db) } > 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "cannot change the hash of a database that holds data",
+            ));
+        }
+        let rc = unsafe { mdbm_sys::mdbm_set_hash(self.db, hash.hash_constant() as libc::c_int) };
+        if rc != 1 {
+            return Err(last_error("set_hash"));
+        }
+        Ok(())
     }
-}
+
+    /// Schedule the mapped pages to be written back to the file.
+    ///
+    /// This returns without waiting for the writes, so the data is visible
+    /// to other readers of the file but may not survive a crash.
+    pub fn sync(&self) -> Result<(), io::Error> {
+        if unsafe { mdbm_sys::mdbm_sync(self.db) } != 0 {
+            return Err(last_error("sync"));
+        }
+        Ok(())
+    }
+
+    /// Write the mapped pages back to the file and wait until they reach
+    /// the disk, so the data survives a crash.
+    ///
+    /// Slower than `sync`.
+    pub fn fsync(&self) -> Result<(), io::Error> {
+        if unsafe { mdbm_sys::mdbm_fsync(self.db) } != 0 {
+            return Err(last_error("fsync"));
+        }
+        Ok(())
+    }
+
+    /// `fsync` and close the database, reporting a failed fsync.
+    ///
+    /// Dropping the handle only `sync`s, and has to ignore errors.
+    pub fn close(mut self) -> Result<(), io::Error> {
+        let result = self.fsync();
+        unsafe { mdbm_sys::mdbm_close(self.db) };
+        // Tell Drop the handle is already closed.
+        self.db = ptr::null_mut();
+        result
+    }
+
+    /// Build an error for a failed lock `operation` on this handle.
+    ///
+    /// MDBM saves the errno of a failed lock on the handle itself, but only
+    /// for locks, and never clears it, so other calls must use `last_error`.
+    /// Falls back to `errno` if the handle has nothing saved.
+    fn last_lock_error(&self, operation: &'static str) -> io::Error {
+        let errno = unsafe { mdbm_sys::mdbm_get_errno(self.db) };
+        if errno != 0 {
+            MdbmError::from_errno(operation, errno).into()
+        } else {
+            last_error(operation)
+        }
+    }
+
+    /// Open another handle on the same database.
+    ///
+    /// MDBM handles must not be shared between threads, so give each thread
+    /// its own duplicate. The duplicate starts without a stats callback.
+    pub fn dup_handle(&self) -> Result<MDBM, io::Error> {
+        let db = unsafe { mdbm_sys::mdbm_dup_handle(self.db, 0) };
+        if db.is_null() {
+            return Err(last_error("dup_handle"));
+        }
+        Ok(MDBM {
+            db,
+            path: self.path.clone(),
+            large_objects: self.large_objects,
+            default_store_mode: self.default_store_mode,
+            stats_callback: None,
+            window_growth: Cell::new(None),
+        })
+    }
+
+    /// The raw `mdbm_sys` handle, for calling functions this crate doesn't
+    /// wrap.
+    ///
+    /// # Safety
+    ///
+    /// The pointer is only valid while this `MDBM` is alive. Don't close it,
+    /// and don't call anything that changes state this wrapper tracks, such
+    /// as the stats callback.
+    pub unsafe fn as_raw(&self) -> *mut mdbm_sys::MDBM {
+        self.db
+    }
+
+    /// Give up ownership of the raw handle without closing it, like
+    /// `CString::into_raw`. Use `from_raw` to take it back.
+    ///
+    /// A stats callback, if set, is unregistered and dropped first, since it
+    /// can't outlive this wrapper.
+    pub fn into_raw(mut self) -> *mut mdbm_sys::MDBM {
+        if self.stats_callback.is_some() {
+            unsafe { mdbm_sys::mdbm_set_stats_func(self.db, 0, None, ptr::null_mut()) };
+        }
+        let db = self.db;
+        // Tell Drop the handle now belongs to someone else.
+        self.db = ptr::null_mut();
+        db
+    }
+
+    /// Take ownership of a raw handle, such as one from `into_raw`.
+    ///
+    /// The wrapper can't recover what it was opened with, so `path` is empty
+    /// and the default store mode is `Replace`. Store errors won't suggest
+    /// `large_objects` either way.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must be an open handle that nothing else will close or use
+    /// while the returned `MDBM` owns it.
+    pub unsafe fn from_raw(ptr: *mut mdbm_sys::MDBM) -> MDBM {
+        MDBM {
+            db: ptr,
+            path: PathBuf::new(),
+            large_objects: false,
+            default_store_mode: StoreMode::Replace,
+            stats_callback: None,
+            window_growth: Cell::new(None),
+        }
+    }
+
+    /// The path this handle was opened with, as passed to `new`.
+    ///
+    /// For `in_memory` databases the file no longer exists.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Get the hash function used by the database, if it is one we know.
+    pub fn get_hash(&self) -> Option<HashFunction> {
+        let c = unsafe { mdbm_sys::mdbm_get_hash(self.db) };
+        HashFunction::from_constant(c as u32)
+    }
+
+    /// Set a key.
+    pub fn set<'k, 'v, K, V>(&self, key: &'k K, value: &'v V, flags: isize) -> Result<(), io::Error>
+    where
+        K: AsDatum<'k> + ?Sized,
+        V: AsDatum<'v> + ?Sized,
+    {
+        self.store_datum(&key.as_datum(), &value.as_datum(), flags)
+            .map(|_| ())
+    }
+
+    /// Set a key, putting the value on the large-object heap whatever its
+    /// size.
+    ///
+    /// MDBM picks the heap by comparing a value's size with the spill size,
+    /// so this lowers the spill size to the value's size for the one store
+    /// and then puts it back, holding the database lock throughout. Requires
+    /// `large_objects`. An empty value can't go on the heap and is stored
+    /// inline as usual.
+    pub fn store_large<'k, 'v, K, V>(
+        &self,
+        key: &'k K,
+        value: &'v V,
+        flags: isize,
+    ) -> Result<(), io::Error>
+    where
+        K: AsDatum<'k> + ?Sized,
+        V: AsDatum<'v> + ?Sized,
+    {
+        if !self.large_objects {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "store_large needs a database opened with large_objects",
+            ));
+        }
+        let value = value.as_datum();
+        let key = key.as_datum();
+        if value.bytes.is_empty() {
+            return self.store_datum(&key, &value, flags).map(|_| ());
+        }
+        let spill_size = value.bytes.len().min(libc::c_int::MAX as usize) as libc::c_int;
+
+        let _lock = self.lock_db()?;
+        let previous = self.get_stats()?.s_large_threshold as libc::c_int;
+        if unsafe { mdbm_sys::mdbm_setspillsize(self.db, spill_size) } != 0 {
+            return Err(last_error("setspillsize"));
+        }
+        let stored = self.store_datum(&key, &value, flags).map(|_| ());
+        if unsafe { mdbm_sys::mdbm_setspillsize(self.db, previous) } != 0 && stored.is_ok() {
+            return Err(last_error("setspillsize"));
+        }
+        stored
+    }
+
+    /// Set a key, failing with `InvalidInput` instead of spilling the value
+    /// to the large-object heap.
+    ///
+    /// With `large_objects`, values at or above the spill size are rejected
+    /// before storing. Without it MDBM has no heap to spill to, so this is
+    /// the same as `set`.
+    pub fn store_inline_only<'k, 'v, K, V>(
+        &self,
+        key: &'k K,
+        value: &'v V,
+        flags: isize,
+    ) -> Result<(), io::Error>
+    where
+        K: AsDatum<'k> + ?Sized,
+        V: AsDatum<'v> + ?Sized,
+    {
+        let value = value.as_datum();
+        if self.large_objects {
+            let spill_size = self.get_stats()?.s_large_threshold as usize;
+            if spill_size > 0 && value.bytes.len() >= spill_size {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "value of {} bytes would spill to the large-object heap \
+                         at {} bytes",
+                        value.bytes.len(),
+                        spill_size
+                    ),
+                ));
+            }
+        }
+        self.store_datum(&key.as_datum(), &value, flags).map(|_| ())
+    }
+
+    /// Fetch a copy of a key's value, or `None` if the key is absent.
+    ///
+    /// The key is locked only while the value is copied out.
+    pub fn fetch<'k, K>(&self, key: &'k K) -> Result<Option<OwnedDatum>, io::Error>
+    where
+        K: AsDatum<'k> + ?Sized,
+    {
+        let value = {
+            let lock = self.lock_datum(key.as_datum(), 0)?;
+            lock.get().map(OwnedDatum::new)
+        };
+        self.grow_window_if_thrashing();
+        Ok(value)
+    }
+
+    /// Fetch a fixed-size value, failing with `InvalidData` if the stored
+    /// value isn't exactly `N` bytes.
+    pub fn fetch_fixed<'k, K, const N: usize>(
+        &self,
+        key: &'k K,
+    ) -> Result<Option<[u8; N]>, io::Error>
+    where
+        K: AsDatum<'k> + ?Sized,
+    {
+        let lock = self.lock_datum(key.as_datum(), 0)?;
+        let value = match lock.get() {
+            Some(value) => value,
+            None => return Ok(None),
+        };
+        if value.len() != N {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("expected a {}-byte value, found {} bytes", N, value.len()),
+            ));
+        }
+        let mut out = [0u8; N];
+        out.copy_from_slice(value);
+        Ok(Some(out))
+    }
+
+    /// Store a value that expires after `ttl`.
+    ///
+    /// MDBM has no expiry of its own, so the value is stored behind an
+    /// 8-byte big-endian expiry time, in milliseconds since the Unix epoch.
+    /// Read it back with `fetch_live`; plain `fetch` sees the prefix.
+    pub fn store_with_ttl<'k, 'v, K, V>(
+        &self,
+        key: &'k K,
+        value: &'v V,
+        ttl: Duration,
+    ) -> Result<(), io::Error>
+    where
+        K: AsDatum<'k> + ?Sized,
+        V: AsDatum<'v> + ?Sized,
+    {
+        let ttl_ms = ttl.as_millis().min(u64::MAX as u128) as u64;
+        let expiry = now_millis().saturating_add(ttl_ms);
+        let value = value.as_datum();
+        let mut stored = Vec::with_capacity(TTL_PREFIX_LEN + value.bytes.len());
+        stored.extend_from_slice(&expiry.to_be_bytes());
+        stored.extend_from_slice(value.bytes);
+        self.store_datum(
+            &key.as_datum(),
+            &Datum::new(&stored),
+            mdbm_sys::MDBM_REPLACE as isize,
+        )
+        .map(|_| ())
+    }
+
+    /// Fetch a value stored by `store_with_ttl`, without its expiry prefix.
+    ///
+    /// An expired value is deleted, under the key's write lock, and reported
+    /// as absent. A value too short to hold the prefix is `InvalidData`.
+    pub fn fetch_live<'k, K>(&self, key: &'k K) -> Result<Option<OwnedDatum>, io::Error>
+    where
+        K: AsDatum<'k> + ?Sized,
+    {
+        let key = key.as_datum();
+        let lock = self.lock_datum(key.clone(), WRITE_LOCK)?;
+        let value = match lock.get() {
+            Some(value) => value,
+            None => return Ok(None),
+        };
+        let expiry = ttl_expiry(value).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                "value is too short for an expiry prefix",
+            )
+        })?;
+        if expiry <= now_millis() {
+            self.delete(key.bytes)?;
+            return Ok(None);
+        }
+        Ok(Some(OwnedDatum::new(&value[TTL_PREFIX_LEN..])))
+    }
+
+    /// Set a key to `value` compressed by `compressor`, replacing any
+    /// existing value.
+    ///
+    /// The stored value starts with a header byte saying whether it was
+    /// compressed: values that don't shrink are kept as they are. Read it
+    /// back with `fetch_decompressed`; plain `fetch` sees the header.
+    pub fn store_compressed<'k, 'v, K, V>(
+        &self,
+        key: &'k K,
+        value: &'v V,
+        compressor: &dyn Compressor,
+    ) -> Result<(), io::Error>
+    where
+        K: AsDatum<'k> + ?Sized,
+        V: AsDatum<'v> + ?Sized,
+    {
+        let value = value.as_datum();
+        let compressed = compressor.compress(value.bytes);
+        let (header, body) = if compressed.len() < value.bytes.len() {
+            (STORED_COMPRESSED, &compressed[..])
+        } else {
+            (STORED_RAW, value.bytes)
+        };
+        let mut stored = Vec::with_capacity(1 + body.len());
+        stored.push(header);
+        stored.extend_from_slice(body);
+        self.store_datum(
+            &key.as_datum(),
+            &Datum::new(&stored),
+            mdbm_sys::MDBM_REPLACE as isize,
+        )
+        .map(|_| ())
+    }
+
+    /// Fetch a value stored by `store_compressed`, decompressing it if
+    /// needed, or `None` if the key is absent.
+    ///
+    /// A value without a valid header is `InvalidData`.
+    pub fn fetch_decompressed<'k, K>(
+        &self,
+        key: &'k K,
+        compressor: &dyn Compressor,
+    ) -> Result<Option<Vec<u8>>, io::Error>
+    where
+        K: AsDatum<'k> + ?Sized,
+    {
+        let value = match self.fetch(key)? {
+            Some(value) => value,
+            None => return Ok(None),
+        };
+        match value.split_first() {
+            Some((&STORED_RAW, body)) => Ok(Some(body.to_vec())),
+            Some((&STORED_COMPRESSED, body)) => compressor.decompress(body).map(Some),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "value has no compression header",
+            )),
+        }
+    }
+
+    /// Fault in the pages holding `keys` and their values, to warm a known
+    /// hot set.
+    ///
+    /// Each value is read in full and discarded. Missing keys are skipped.
+    pub fn preload_keys<'a, I, K>(&self, keys: I) -> Result<(), io::Error>
+    where
+        I: IntoIterator<Item = &'a K>,
+        K: AsDatum<'a> + ?Sized + 'a,
+    {
+        for key in keys {
+            self.fetch(key)?;
+        }
+        Ok(())
+    }
+
+    /// Set the mode `put` stores with. Defaults to `StoreMode::Replace`.
+    ///
+    /// With `StoreMode::InsertDuplicate` the handle acts as a multimap:
+    /// every `put` adds a value, and `get_all` reads them back.
+    pub fn set_default_store_mode(&mut self, mode: StoreMode) {
+        self.default_store_mode = mode;
+    }
+
+    /// Set a key using the handle's default store mode.
+    ///
+    /// Unlike `set`, a store that `StoreMode::Insert` skips because the key
+    /// exists is an `AlreadyExists` error.
+    pub fn put<'k, 'v, K, V>(&self, key: &'k K, value: &'v V) -> Result<(), io::Error>
+    where
+        K: AsDatum<'k> + ?Sized,
+        V: AsDatum<'v> + ?Sized,
+    {
+        self.store_with_mode(&key.as_datum(), &value.as_datum(), self.default_store_mode)
+    }
+
+    /// Store with `mode`, treating a store that `StoreMode::Insert` skips as
+    /// an `AlreadyExists` error.
+    fn store_with_mode(
+        &self,
+        key: &Datum,
+        value: &Datum,
+        mode: StoreMode,
+    ) -> Result<(), io::Error> {
+        if self.store_datum(key, value, mode.flag())? == 1 {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                "key already exists",
+            ));
+        }
+        Ok(())
+    }
+
+    /// Fetch a key's value as a `String`, or `None` if the key is absent.
+    ///
+    /// A value that isn't valid UTF-8 is an `InvalidData` error.
+    pub fn fetch_str<'k, K>(&self, key: &'k K) -> Result<Option<String>, io::Error>
+    where
+        K: AsDatum<'k> + ?Sized,
+    {
+        match self.fetch(key)? {
+            Some(value) => String::from_utf8(value.into_vec())
+                .map(Some)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+            None => Ok(None),
+        }
+    }
+
+    /// Store a string value with `mode`.
+    ///
+    /// As with `put`, a store that `StoreMode::Insert` skips because the key
+    /// exists is an `AlreadyExists` error.
+    pub fn store_str<'k, K>(
+        &self,
+        key: &'k K,
+        value: &str,
+        mode: StoreMode,
+    ) -> Result<(), io::Error>
+    where
+        K: AsDatum<'k> + ?Sized,
+    {
+        self.store_with_mode(&key.as_datum(), &Datum::new(value.as_bytes()), mode)
+    }
+
+    /// Fetch copies of every value stored under `key`, including duplicates,
+    /// or an empty `Vec` if the key is absent.
+    pub fn get_all<'k, K>(&self, key: &'k K) -> Result<Vec<OwnedDatum>, io::Error>
+    where
+        K: AsDatum<'k> + ?Sized,
+    {
+        let lock = self.lock_datum(key.as_datum(), 0)?;
+        let values = lock.iter_dups().map(OwnedDatum::new).collect();
+        Ok(values)
+    }
+
+    /// Fetch a copy of a key's value along with the number of the page
+    /// holding it, or `None` if the key is absent.
+    ///
+    /// Both are read under one lock on the key, so the entry can't move
+    /// between pages in between.
+    pub fn fetch_located<'k, K>(&self, key: &'k K) -> Result<Option<(OwnedDatum, u32)>, io::Error>
+    where
+        K: AsDatum<'k> + ?Sized,
+    {
+        let lock = self.lock_datum(key.as_datum(), 0)?;
+        let value = match lock.get() {
+            Some(value) => OwnedDatum::new(value),
+            None => return Ok(None),
+        };
+        let page = self.page_for_datum(&lock.key)?;
+        Ok(Some((value, page)))
+    }
+
+    /// Store a value, replacing any existing one, then read it back and fail
+    /// with `InvalidData` if the bytes differ.
+    ///
+    /// Both steps happen under the key's write lock, so a concurrent writer
+    /// can't cause a false mismatch. Meant as a self-check for tests and
+    /// canaries.
+    pub fn verify_store<'k, 'v, K, V>(&self, key: &'k K, value: &'v V) -> Result<(), io::Error>
+    where
+        K: AsDatum<'k> + ?Sized,
+        V: AsDatum<'v> + ?Sized,
+    {
+        let (key, value) = (key.as_datum(), value.as_datum());
+        let lock = self.lock_datum(key.clone(), WRITE_LOCK)?;
+        self.store_datum(&key, &value, mdbm_sys::MDBM_REPLACE as isize)?;
+        match lock.get() {
+            Some(stored) if stored == value.bytes => Ok(()),
+            Some(stored) => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "stored {} bytes but read back {} different bytes",
+                    value.bytes.len(),
+                    stored.len()
+                ),
+            )),
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "stored value could not be read back",
+            )),
+        }
+    }
+
+    /// Set a key with `mode` and explicit `StoreFlags`.
+    ///
+    /// Returns `true` if the value was stored and `false` if
+    /// `StoreMode::Insert` found the key already present. `RESERVE` is
+    /// rejected with `InvalidInput`.
+    pub fn store_flags<'k, 'v, K, V>(
+        &self,
+        key: &'k K,
+        value: &'v V,
+        mode: StoreMode,
+        flags: StoreFlags,
+    ) -> Result<bool, io::Error>
+    where
+        K: AsDatum<'k> + ?Sized,
+        V: AsDatum<'v> + ?Sized,
+    {
+        if flags.contains(StoreFlags::RESERVE) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "store_flags can't reserve; use reserve_value",
+            ));
+        }
+        let flags = mode.flag() | flags.bits() as isize;
+        let rc = self.store_datum(&key.as_datum(), &value.as_datum(), flags)?;
+        Ok(rc == 0)
+    }
+
+    /// Set a key only if it is not already present.
+    ///
+    /// Returns `true` if the value was stored and `false` if the key already
+    /// existed, in which case the stored value is left untouched.
+    pub fn store_if_absent<'k, 'v, K, V>(&self, key: &'k K, value: &'v V) -> Result<bool, io::Error>
+    where
+        K: AsDatum<'k> + ?Sized,
+        V: AsDatum<'v> + ?Sized,
+    {
+        let rc = self.store_datum(
+            &key.as_datum(),
+            &value.as_datum(),
+            mdbm_sys::MDBM_INSERT as isize,
+        )?;
+        Ok(rc == 0)
+    }
+
+    /// Replace the value of a key that already exists.
+    ///
+    /// Stores with `MDBM_MODIFY` under a write lock on the key. A missing key
+    /// is a `NotFound` error, and is left missing.
+    pub fn update<'k, 'v, K, V>(&self, key: &'k K, value: &'v V) -> Result<(), io::Error>
+    where
+        K: AsDatum<'k> + ?Sized,
+        V: AsDatum<'v> + ?Sized,
+    {
+        let key = key.as_datum();
+        let lock = self.lock_datum(key.clone(), WRITE_LOCK)?;
+        if lock.get().is_none() {
+            return Err(io::Error::new(io::ErrorKind::NotFound, "key not found"));
+        }
+        self.store_datum(&key, &value.as_datum(), mdbm_sys::MDBM_MODIFY as isize)?;
+        Ok(())
+    }
+
+    /// Store `new` under `key` only if the current value matches `expected`,
+    /// where `None` means "only if absent".
+    ///
+    /// The comparison and store happen under a write lock on the key. Returns
+    /// whether the value was swapped in.
+    pub fn compare_and_swap<'k, 'o, 'n, K, O, N>(
+        &self,
+        key: &'k K,
+        expected: Option<&'o O>,
+        new: &'n N,
+    ) -> Result<bool, io::Error>
+    where
+        K: AsDatum<'k> + ?Sized,
+        O: AsDatum<'o> + ?Sized,
+        N: AsDatum<'n> + ?Sized,
+    {
+        let key = key.as_datum();
+        let lock = self.lock_datum(key.clone(), WRITE_LOCK)?;
+        let expected = expected.map(|e| e.as_datum().bytes);
+        if lock.get() != expected {
+            return Ok(false);
+        }
+        self.store_datum(&key, &new.as_datum(), mdbm_sys::MDBM_REPLACE as isize)?;
+        Ok(true)
+    }
+
+    /// Get a copy of a key's value, first storing `default()` if the key is
+    /// absent.
+    ///
+    /// The lookup and insert happen under a write lock on the key, so
+    /// `default` runs at most once per missing key even with concurrent
+    /// callers.
+    pub fn entry_or_default<'k, K, V, F>(
+        &self,
+        key: &'k K,
+        default: F,
+    ) -> Result<Vec<u8>, io::Error>
+    where
+        K: AsDatum<'k> + ?Sized,
+        V: AsRef<[u8]>,
+        F: FnOnce() -> V,
+    {
+        let key = key.as_datum();
+        let lock = self.lock_datum(key.clone(), WRITE_LOCK)?;
+        if let Some(value) = lock.get() {
+            return Ok(value.to_vec());
+        }
+        let value = default();
+        self.store_datum(
+            &key,
+            &Datum::new(value.as_ref()),
+            mdbm_sys::MDBM_INSERT as isize,
+        )?;
+        Ok(value.as_ref().to_vec())
+    }
+
+    /// Copy the value under `from` to `to`, replacing any value `to` had.
+    ///
+    /// Both keys are write-locked for the copy, in sorted byte order as with
+    /// `lock_many`, so concurrent copies between the same two keys can't
+    /// deadlock. Returns whether `from` existed; if it didn't, `to` is left
+    /// untouched.
+    pub fn copy_value<'a, A, B>(&self, from: &'a A, to: &'a B) -> Result<bool, io::Error>
+    where
+        A: AsDatum<'a> + ?Sized,
+        B: AsDatum<'a> + ?Sized,
+    {
+        self.transfer_value(from.as_datum(), to.as_datum(), false)
+    }
+
+    /// Move the value under `from` to `to`, replacing any value `to` had,
+    /// and delete `from`.
+    ///
+    /// Locks as `copy_value` does, so lockers of either key never see the
+    /// value under both keys or under neither.
+    /// Returns whether `from` existed. Renaming a key to itself leaves it as
+    /// it was.
+    pub fn rename_key<'a, A, B>(&self, from: &'a A, to: &'a B) -> Result<bool, io::Error>
+    where
+        A: AsDatum<'a> + ?Sized,
+        B: AsDatum<'a> + ?Sized,
+    {
+        self.transfer_value(
+            from.as_datum(),
+            to.as_datum(),
+            from.as_datum() != to.as_datum(),
+        )
+    }
+
+    fn transfer_value(&self, from: Datum, to: Datum, delete_from: bool) -> Result<bool, io::Error> {
+        let keys = [from.bytes, to.bytes];
+        let _locks = self.lock_many(&keys, WRITE_LOCK)?;
+        let value = match self.fetch(from.bytes)? {
+            Some(value) => value,
+            None => return Ok(false),
+        };
+        self.store_datum(&to, &Datum::new(&value), mdbm_sys::MDBM_REPLACE as isize)?;
+        if delete_from {
+            self.delete(from.bytes)?;
+        }
+        Ok(true)
+    }
+
+    /// Call `mdbm_store`, returning its non-error return code: 0 when the
+    /// value was stored, 1 when `MDBM_INSERT` found the key already present.
+    fn store_datum(
+        &self,
+        key: &Datum,
+        value: &Datum,
+        flags: isize,
+    ) -> Result<libc::c_int, io::Error> {
+        let rc = unsafe {
+            mdbm_sys::mdbm_store(
+                self.db,
+                to_raw_datum(key),
+                to_raw_datum(value),
+                flags as libc::c_int,
+            )
+        };
+        trace_op!(
+            op = "store",
+            key_len = key.bytes.len() as u64,
+            value_len = value.bytes.len() as u64,
+            rc = rc
+        );
+
+        if rc == -1 {
+            Err(self.store_error(key, value))
+        } else {
+            Ok(rc)
+        }
+    }
+
+    /// Explain a failed store, calling out entries too big for a page when
+    /// large objects are off, which MDBM only reports as a bare errno.
+    ///
+    /// MDBM's exact bookkeeping per page and per entry isn't exposed, so an
+    /// entry within `PAGE_OVERHEAD` of the page size only "may not" fit.
+    fn store_error(&self, key: &Datum, value: &Datum) -> io::Error {
+        let err = last_error("store");
+        if self.large_objects {
+            return err;
+        }
+        let page_size = unsafe { mdbm_sys::mdbm_get_page_size(self.db) };
+        let needed = key.bytes.len() + value.bytes.len();
+        if page_size > 0 && needed + PAGE_OVERHEAD > page_size as usize {
+            let fit = if needed > page_size as usize {
+                "does not"
+            } else {
+                "may not"
+            };
+            return io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "key and value of {} bytes {} fit in a page of {} bytes; \
+                     open with large_objects",
+                    needed, fit, page_size
+                ),
+            );
+        }
+        err
+    }
+
+    /// Delete a key.
+    pub fn delete<'k, K>(&self, key: &'k K) -> Result<(), io::Error>
+    where
+        K: AsDatum<'k> + ?Sized,
+    {
+        let rc = unsafe { mdbm_sys::mdbm_delete(self.db, to_raw_datum(&key.as_datum())) };
+        if rc == -1 {
+            Err(last_error("delete"))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Store exactly `total_size` bytes read from `reader` under `key`,
+    /// replacing any existing value.
+    ///
+    /// The space is reserved with `reserve_value` and filled straight from
+    /// the reader, so the value is never buffered in memory. If the reader
+    /// fails or runs out early, the key is deleted and the error returned;
+    /// a replaced value is lost either way.
+    pub fn store_from_reader<'a, K, R>(
+        &'a self,
+        key: &'a K,
+        mut reader: R,
+        total_size: usize,
+    ) -> Result<(), io::Error>
+    where
+        K: AsDatum<'a> + ?Sized,
+        R: io::Read,
+    {
+        let ValueWriter { bytes, _lock: lock } = self.reserve_value(key, total_size)?;
+        if let Err(e) = reader.read_exact(bytes) {
+            // `bytes` is spent before the delete frees its slot. The lock is
+            // still held; MDBM locks are recursive.
+            self.delete(key)?;
+            drop(lock);
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// Delete each of `keys`, returning how many were present.
+    ///
+    /// The database lock is taken once for the whole batch rather than per
+    /// key. Missing keys are skipped.
+    pub fn delete_batch<'k, I, K>(&self, keys: I) -> Result<u64, io::Error>
+    where
+        I: IntoIterator<Item = &'k K>,
+        K: AsDatum<'k> + ?Sized + 'k,
+    {
+        let _lock = self.lock_db()?;
+        let mut removed = 0;
+        for key in keys {
+            let key = to_raw_datum(&key.as_datum());
+            let present = unsafe { !mdbm_sys::mdbm_fetch(self.db, key).dptr.is_null() };
+            if !present {
+                continue;
+            }
+            if unsafe { mdbm_sys::mdbm_delete(self.db, key) } == -1 {
+                return Err(last_error("delete"));
+            }
+            removed += 1;
+        }
+        Ok(removed)
+    }
+
+    /// Lock a key and borrow its value, or `None` if the key is absent.
+    ///
+    /// The `ValueGuard` holds both the lock and the value, so the key stays
+    /// locked until it drops.
+    pub fn get_ref<'a, K>(&'a self, key: &'a K) -> Result<Option<ValueGuard<'a>>, io::Error>
+    where
+        K: AsDatum<'a> + ?Sized,
+    {
+        let lock = self.lock_datum(key.as_datum(), 0)?;
+        let value = unsafe { mdbm_sys::mdbm_fetch(self.db, to_raw_datum(&lock.key)) };
+        trace_op!(
+            op = "fetch",
+            key_len = lock.key.bytes.len() as u64,
+            found = !value.dptr.is_null()
+        );
+        if value.dptr.is_null() {
+            return Ok(None);
+        }
+        // The value stays put while the key is locked, and the guard keeps
+        // the lock.
+        let bytes = unsafe { from_raw_datum(&value) };
+        Ok(Some(ValueGuard { bytes, _lock: lock }))
+    }
+
+    /// Store `size` bytes under `key` and return them for writing in place.
+    ///
+    /// Uses `MDBM_RESERVE`, so the value is written straight into the
+    /// database's pages with no intermediate copy. The space starts zeroed,
+    /// replaces any existing value, and stays write-locked until the
+    /// `ValueWriter` drops, so other lockers only ever see the finished value.
+    ///
+    /// Fails with `InvalidInput` if `size` doesn't fit in MDBM's `int` sizes.
+    pub fn reserve_value<'a, K>(
+        &'a self,
+        key: &'a K,
+        size: usize,
+    ) -> Result<ValueWriter<'a>, io::Error>
+    where
+        K: AsDatum<'a> + ?Sized,
+    {
+        if size > libc::c_int::MAX as usize {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("cannot reserve a value of {} bytes", size),
+            ));
+        }
+        let lock = self.lock_datum(key.as_datum(), WRITE_LOCK)?;
+        let mut raw_key = to_raw_datum(&lock.key);
+        let mut raw_value = mdbm_sys::datum {
+            dptr: ptr::null_mut(),
+            dsize: size as libc::c_int,
+        };
+        let mut iter = new_iter();
+        let rc = unsafe {
+            mdbm_sys::mdbm_store_r(
+                self.db,
+                &mut raw_key,
+                &mut raw_value,
+                (mdbm_sys::MDBM_REPLACE | mdbm_sys::MDBM_RESERVE) as libc::c_int,
+                &mut iter,
+            )
+        };
+        trace_op!(
+            op = "reserve",
+            key_len = lock.key.bytes.len() as u64,
+            value_len = size as u64,
+            rc = rc
+        );
+        if rc == -1 {
+            return Err(last_error("reserve"));
+        }
+
+        let bytes = unsafe { slice::from_raw_parts_mut(raw_value.dptr as *mut u8, size) };
+        for b in bytes.iter_mut() {
+            *b = 0;
+        }
+        Ok(ValueWriter { bytes, _lock: lock })
+    }
+
+    /// Delete every key starting with `prefix`, returning how many were
+    /// removed.
+    ///
+    /// Walks the whole database once, under a single lock.
+    pub fn delete_prefix(&self, prefix: &[u8]) -> Result<u64, io::Error> {
+        let removed = self.delete_where(|key, _| key.starts_with(prefix))?;
+        trace_op!(
+            op = "delete_prefix",
+            prefix_len = prefix.len() as u64,
+            removed = removed
+        );
+        Ok(removed)
+    }
+
+    /// Delete every entry under `key_prefix` that was stored by
+    /// `store_with_ttl` and has expired, returning how many were removed.
+    ///
+    /// Values carry no mark of their own, so every value under `key_prefix`
+    /// is read as having an expiry prefix and keys outside it are never
+    /// looked at: keep TTL entries under a prefix that nothing else uses.
+    /// Values shorter than the expiry prefix are left alone.
+    pub fn sweep_expired(&self, key_prefix: &[u8]) -> Result<u64, io::Error> {
+        let now = now_millis();
+        let removed = self.delete_where(|key, value| {
+            key.starts_with(key_prefix)
+                && matches!(ttl_expiry(value), Some(expiry) if expiry <= now)
+        })?;
+        trace_op!(op = "sweep_expired", removed = removed);
+        Ok(removed)
+    }
+
+    /// Delete every entry matching `pred`, in one walk under the database
+    /// lock.
+    ///
+    /// Deletes go through the iterator with `mdbm_delete_r`, so the walk
+    /// stays valid as entries go.
+    fn delete_where<F: FnMut(&[u8], &[u8]) -> bool>(&self, mut pred: F) -> Result<u64, io::Error> {
+        let _lock = self.lock_db()?;
+        let mut iter = new_iter();
+        let mut removed = 0;
+        let mut kv = unsafe { mdbm_sys::mdbm_first_r(self.db, &mut iter) };
+        while !kv.key.dptr.is_null() {
+            let matched = unsafe { pred(from_raw_datum(&kv.key), from_raw_datum(&kv.val)) };
+            if matched {
+                let rc = unsafe { mdbm_sys::mdbm_delete_r(self.db, &mut iter) };
+                if rc == -1 {
+                    return Err(last_error("delete"));
+                }
+                removed += 1;
+            }
+            kv = unsafe { mdbm_sys::mdbm_next_r(self.db, &mut iter) };
+        }
+        Ok(removed)
+    }
+
+    /// Run `check` on every entry, returning each key it rejected along with
+    /// its message.
+    ///
+    /// Runs under the database lock. `check` sees a copy of each entry, in
+    /// buffers reused from one entry to the next.
+    pub fn validate<F>(&self, mut check: F) -> Result<Vec<(Vec<u8>, String)>, io::Error>
+    where
+        F: FnMut(&[u8], &[u8]) -> Result<(), String>,
+    {
+        let mut failures = Vec::new();
+        self.scan_copied(|key, value| {
+            if let Err(msg) = check(key, value) {
+                failures.push((key.to_vec(), msg));
+            }
+        })?;
+        Ok(failures)
+    }
+
+    /// Call `f` on a copy of every entry, under the database lock.
+    ///
+    /// Nothing points into the database while `f` runs, so `f` may use the
+    /// database, though changes it makes may or may not show up later in
+    /// the scan.
+    fn scan_copied<F: FnMut(&[u8], &[u8])>(&self, mut f: F) -> Result<(), io::Error> {
+        let _lock = self.lock_db()?;
+        let (mut key, mut value) = (Vec::new(), Vec::new());
+        let mut iter = new_iter();
+        let mut kv = unsafe { mdbm_sys::mdbm_first_r(self.db, &mut iter) };
+        while !kv.key.dptr.is_null() {
+            key.clear();
+            value.clear();
+            unsafe {
+                key.extend_from_slice(from_raw_datum(&kv.key));
+                value.extend_from_slice(from_raw_datum(&kv.val));
+            }
+            f(&key, &value);
+            kv = unsafe { mdbm_sys::mdbm_next_r(self.db, &mut iter) };
+        }
+        Ok(())
+    }
+
+    /// Copy out every entry `pred` accepts.
+    ///
+    /// MDBM has no secondary indexes, so this is a full scan under the
+    /// database lock. `pred` sees a copy of each entry, in buffers reused
+    /// from one entry to the next.
+    pub fn find<F>(&self, pred: F) -> Result<OwnedEntries, io::Error>
+    where
+        F: Fn(&[u8], &[u8]) -> bool,
+    {
+        let mut found = Vec::new();
+        self.scan_copied(|key, value| {
+            if pred(key, value) {
+                found.push((key.to_vec(), value.to_vec()));
+            }
+        })?;
+        Ok(found)
+    }
+
+    /// Pick `n` entries uniformly at random, or every entry if there are
+    /// fewer than `n`.
+    ///
+    /// Uses reservoir sampling over one pass under the database lock, so
+    /// only the sampled entries are ever copied. The order of the result is
+    /// arbitrary.
+    pub fn sample(&self, n: usize) -> Result<OwnedEntries, io::Error> {
+        let mut rng = XorShift::new();
+        let mut sample = Vec::with_capacity(n);
+        let mut seen: u64 = 0;
+        self.scan_in_place(|key, value| {
+            seen += 1;
+            if sample.len() < n {
+                sample.push((key.to_vec(), value.to_vec()));
+                return;
+            }
+            let j = rng.below(seen) as usize;
+            if j < n {
+                sample[j] = (key.to_vec(), value.to_vec());
+            }
+        })?;
+        Ok(sample)
+    }
+
+    /// Call `f` on every entry in place, under the database lock.
+    ///
+    /// `f` must not use the database: the entries it sees point into pages
+    /// that any change could move.
+    fn scan_in_place<F: FnMut(&[u8], &[u8])>(&self, mut f: F) -> Result<(), io::Error> {
+        let _lock = self.lock_db()?;
+        let mut iter = new_iter();
+        let mut kv = unsafe { mdbm_sys::mdbm_first_r(self.db, &mut iter) };
+        while !kv.key.dptr.is_null() {
+            unsafe { f(from_raw_datum(&kv.key), from_raw_datum(&kv.val)) };
+            kv = unsafe { mdbm_sys::mdbm_next_r(self.db, &mut iter) };
+        }
+        Ok(())
+    }
+
+    /// Delete a key only if its current value equals `expected`.
+    ///
+    /// The check and the delete happen under a write lock on the key, so no
+    /// other locker can change the value in between. Returns whether the key
+    /// was deleted; a missing key is not an error.
+    pub fn remove_if<'k, 'v, K, V>(&self, key: &'k K, expected: &'v V) -> Result<bool, io::Error>
+    where
+        K: AsDatum<'k> + ?Sized,
+        V: AsDatum<'v> + ?Sized,
+    {
+        let key = key.as_datum();
+        let lock = self.lock_datum(key.clone(), WRITE_LOCK)?;
+        if lock.get() != Some(expected.as_datum().bytes) {
+            return Ok(false);
+        }
+        self.delete(key.bytes)?;
+        Ok(true)
+    }
+
+    /// Copy the database to `path` and open the copy read-only.
+    ///
+    /// The copy is made under the database lock, so it is a consistent
+    /// point-in-time view; this handle keeps serving writes afterwards.
+    pub fn snapshot<P: Into<PathBuf>>(&self, path: P) -> Result<MDBM, io::Error> {
+        let path = path.into();
+        self.copy_to(&path)?;
+        let options = Options {
+            reads: ReadState::ReadOnly,
+            create: false,
+            ..Default::default()
+        };
+        MDBM::new(path, options, 0o644, 0, 0)
+    }
+
+    /// Copy the database to `path` and open the copy for reading and
+    /// writing, as an independent database.
+    ///
+    /// Like `snapshot`, the copy is taken under the database lock.
+    pub fn clone_to<P: Into<PathBuf>>(&self, path: P) -> Result<MDBM, io::Error> {
+        let path = path.into();
+        self.copy_to(&path)?;
+        let options = Options {
+            create: false,
+            large_objects: self.large_objects,
+            ..Default::default()
+        };
+        MDBM::new(path, options, 0o644, 0, 0)
+    }
+
+    /// Write a copy of the database to `path`, under the database lock.
+    fn copy_to(&self, path: &Path) -> Result<(), io::Error> {
+        let file = fs::File::create(path)?;
+        let _lock = self.lock_db()?;
+        let rc = unsafe { mdbm_sys::mdbm_fcopy(self.db, file.as_raw_fd(), 0) };
+        if rc != 0 {
+            return Err(last_error("fcopy"));
+        }
+        Ok(())
+    }
+
+    /// Store every entry of `other` into this database, returning how many
+    /// were stored.
+    ///
+    /// Runs under this database's lock. With `ConflictPolicy::Error`, entries
+    /// merged before the conflicting key stay merged.
+    pub fn merge(&self, other: &MDBM, on_conflict: ConflictPolicy) -> Result<u64, io::Error> {
+        let flags = match on_conflict {
+            ConflictPolicy::Overwrite => mdbm_sys::MDBM_REPLACE,
+            ConflictPolicy::KeepExisting | ConflictPolicy::Error => mdbm_sys::MDBM_INSERT,
+        } as isize;
+
+        let _lock = self.lock_db()?;
+        let mut merged = 0;
+        for (key, value) in other.iter_with_values() {
+            let rc = self.store_datum(&Datum::new(&key), &Datum::new(&value), flags)?;
+            if rc == 0 {
+                merged += 1;
+            } else if on_conflict == ConflictPolicy::Error {
+                return Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    format!(
+                        "key {:?} is in both databases",
+                        String::from_utf8_lossy(&key)
+                    ),
+                ));
+            }
+        }
+        Ok(merged)
+    }
+
+    /// Compare every key and value with `other`.
+    ///
+    /// Holds this database's entries in memory while walking `other`. Neither
+    /// database is locked, so concurrent writers can make the result
+    /// inconsistent.
+    pub fn diff(&self, other: &MDBM) -> Result<DbDiff, io::Error> {
+        let mut ours: BTreeMap<Vec<u8>, Vec<u8>> = self.iter_with_values().collect();
+        let mut diff = DbDiff::default();
+        for (key, value) in other.iter_with_values() {
+            match ours.remove(&key) {
+                None => diff.only_in_other.push(key),
+                Some(ref v) if *v != value => diff.value_mismatch.push(key),
+                Some(_) => {}
+            }
+        }
+        diff.only_in_self = ours.into_keys().collect();
+        diff.only_in_other.sort();
+        diff.value_mismatch.sort();
+        Ok(diff)
+    }
+
+    /// Store a small batch of entries all-or-nothing, replacing existing
+    /// values.
+    ///
+    /// MDBM has no transactions, so this holds the database lock, remembers
+    /// each key's previous value before storing it, and if a store fails,
+    /// puts the previous values back (deleting keys that were absent) before
+    /// returning the error. If the rollback itself fails, that error is
+    /// returned instead, and the batch may be partly applied.
+    pub fn store_transaction<I, K, V>(&self, entries: I) -> Result<(), io::Error>
+    where
+        I: IntoIterator<Item = (K, V)>,
+        K: AsRef<[u8]>,
+        V: AsRef<[u8]>,
+    {
+        let _lock = self.lock_db()?;
+        let mut undo: Vec<(Vec<u8>, Option<OwnedDatum>)> = Vec::new();
+        for (key, value) in entries {
+            let key = key.as_ref();
+            let prior = self.fetch(key)?;
+            let stored = self.store_datum(
+                &Datum::new(key),
+                &Datum::new(value.as_ref()),
+                mdbm_sys::MDBM_REPLACE as isize,
+            );
+            if let Err(e) = stored {
+                // Undo newest first, so a key stored twice ends up with the
+                // value it had before the batch.
+                for (key, prior) in undo.into_iter().rev() {
+                    match prior {
+                        Some(value) => {
+                            self.set(&key[..], &value[..], mdbm_sys::MDBM_REPLACE as isize)?
+                        }
+                        None => self.delete(&key[..])?,
+                    }
+                }
+                return Err(e);
+            }
+            undo.push((key.to_vec(), prior));
+        }
+        Ok(())
+    }
+
+    /// Run `f` while holding the whole-database lock.
+    ///
+    /// The lock is released when `f` returns, or if it panics.
+    pub fn with_lock<R, F: FnOnce(&MDBM) -> R>(&self, f: F) -> Result<R, io::Error> {
+        let _lock = self.lock_db()?;
+        Ok(f(self))
+    }
+
+    /// Take the whole-database lock, released when the guard drops.
+    fn lock_db<'a>(&'a self) -> Result<DbLock<'a>, io::Error> {
+        let rc = unsafe { mdbm_sys::mdbm_lock(self.db) };
+        trace_op!(op = "lock_db", rc = rc);
+        if rc != 1 {
+            return Err(self.last_lock_error("lock_db"));
+        }
+        Ok(DbLock { db: self })
+    }
+
+    /// Take the whole-database lock if it is free, without waiting.
+    ///
+    /// Returns `Ok(None)` if the lock is held elsewhere.
+    pub fn try_lock_db<'a>(&'a self) -> Result<Option<DbLock<'a>>, io::Error> {
+        let rc = unsafe { mdbm_sys::mdbm_trylock(self.db) };
+        trace_op!(op = "trylock_db", rc = rc);
+        if rc == 1 {
+            return Ok(Some(DbLock { db: self }));
+        }
+        let err = self.last_lock_error("trylock_db");
+        match MdbmError::from_io(&err).map(|e| e.errno()) {
+            Some(libc::EAGAIN) | Some(libc::EBUSY) => Ok(None),
+            _ => Err(err),
+        }
+    }
+
+    /// Lock every key starting with `prefix`, for bulk work on a namespace.
+    ///
+    /// MDBM locks either a single key or the whole database. Even
+    /// partitioned locks are chosen by hash, so keys sharing a prefix don't
+    /// share a partition. This therefore takes the whole-database lock; it
+    /// exists to name the intent at the call site, and `prefix` only shows
+    /// up, as its length, in the `tracing` event.
+    #[cfg_attr(not(feature = "tracing"), allow(unused_variables))]
+    pub fn lock_prefix<'a>(&'a self, prefix: &[u8]) -> Result<DbLock<'a>, io::Error> {
+        let lock = self.lock_db()?;
+        trace_op!(op = "lock_prefix", prefix_len = prefix.len() as u64);
+        Ok(lock)
+    }
+
+    /// Lock a key.
+    ///
+    /// Like `std::sync::Mutex`, if a thread in this process panicked while
+    /// holding a write lock on the key, this returns `LockError::Poisoned`,
+    /// which still holds the lock. The key stays poisoned until
+    /// `clear_poison`, and until then every other call that locks it, such
+    /// as `try_lock` or `fetch`, fails with the error that
+    /// `LockError::Poisoned` converts to.
+    pub fn lock<'a, K>(&'a self, key: &'a K, flags: isize) -> Result<Lock<'a>, LockError<'a>>
+    where
+        K: AsDatum<'a> + ?Sized,
+    {
+        self.lock_datum_unchecked(key.as_datum(), flags)?
+            .check_poison()
+    }
+
+    /// Mark a key as no longer poisoned, once its value has been checked or
+    /// repaired after a panic.
+    pub fn clear_poison<'k, K>(&self, key: &'k K) -> Result<(), io::Error>
+    where
+        K: AsDatum<'k> + ?Sized,
+    {
+        let id = (self.file_id()?, key.as_datum().bytes.to_vec());
+        let mut poisoned = POISONED.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(ref mut keys) = *poisoned {
+            if keys.remove(&id) {
+                POISONED_COUNT.fetch_sub(1, Ordering::SeqCst);
+            }
+        }
+        Ok(())
+    }
+
+    /// The device and inode of the database file, which identify it across
+    /// handles.
+    fn file_id(&self) -> Result<(u64, u64), io::Error> {
+        unsafe {
+            let mut st: libc::stat = mem::zeroed();
+            if libc::fstat(mdbm_sys::mdbm_get_fd(self.db), &mut st) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok((st.st_dev as u64, st.st_ino as u64))
+        }
+    }
+
+    /// Lock several keys at once, released together when the guard drops.
+    ///
+    /// Keys are locked in sorted byte order, whatever order they are passed
+    /// in, so two callers locking overlapping sets can't deadlock each other.
+    /// If any lock fails, the ones already taken are released.
+    pub fn lock_many<'a>(
+        &'a self,
+        keys: &'a [&'a [u8]],
+        flags: isize,
+    ) -> Result<MultiLock<'a>, io::Error> {
+        let mut sorted = keys.to_vec();
+        sorted.sort();
+        sorted.dedup();
+
+        let mut locks = Vec::with_capacity(sorted.len());
+        for key in sorted {
+            locks.push(self.lock_datum(Datum::new(key), flags)?);
+        }
+        Ok(MultiLock { _locks: locks })
+    }
+
+    /// Lock a key if no one else holds it, without waiting.
+    ///
+    /// Returns `Ok(None)` if the lock is held elsewhere. Fails if the key is
+    /// poisoned, as with `lock`.
+    pub fn try_lock<'a, K>(
+        &'a self,
+        key: &'a K,
+        flags: isize,
+    ) -> Result<Option<Lock<'a>>, io::Error>
+    where
+        K: AsDatum<'a> + ?Sized,
+    {
+        let key = key.as_datum();
+        let rc = unsafe {
+            mdbm_sys::mdbm_trylock_smart(self.db, &to_raw_datum(&key), flags as libc::c_int)
+        };
+        trace_op!(op = "trylock", key_len = key.bytes.len() as u64, rc = rc);
+
+        if rc == 1 {
+            let lock = Lock {
+                db: self,
+                key,
+                write: flags != 0,
+            };
+            return lock.check_poison().map(Some).map_err(io::Error::from);
+        }
+        let err = self.last_lock_error("trylock");
+        match MdbmError::from_io(&err).map(|e| e.errno()) {
+            Some(libc::EAGAIN) | Some(libc::EBUSY) => Ok(None),
+            _ => Err(err),
+        }
+    }
+
+    /// Lock a key, giving up after `timeout`.
+    ///
+    /// Polls with `try_lock`, backing off between attempts, and returns
+    /// `Ok(None)` if the lock is still held elsewhere at the deadline.
+    pub fn lock_timeout<'a, K>(
+        &'a self,
+        key: &'a K,
+        timeout: Duration,
+        flags: isize,
+    ) -> Result<Option<Lock<'a>>, io::Error>
+    where
+        K: AsDatum<'a> + ?Sized,
+    {
+        const MAX_BACKOFF: Duration = Duration::from_millis(50);
+
+        let deadline = Instant::now() + timeout;
+        let mut backoff = Duration::from_millis(1);
+        loop {
+            if let Some(lock) = self.try_lock(key, flags)? {
+                return Ok(Some(lock));
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Ok(None);
+            }
+            thread::sleep(backoff.min(deadline - now));
+            backoff = (backoff * 2).min(MAX_BACKOFF);
+        }
+    }
+
+    /// Lock a key as `lock` does, but fail on a poisoned key rather than hand
+    /// out the lock.
+    fn lock_datum<'a>(&'a self, key: Datum<'a>, flags: isize) -> Result<Lock<'a>, io::Error> {
+        self.lock_datum_unchecked(key, flags)?
+            .check_poison()
+            .map_err(io::Error::from)
+    }
+
+    fn lock_datum_unchecked<'a>(
+        &'a self,
+        key: Datum<'a>,
+        flags: isize,
+    ) -> Result<Lock<'a>, io::Error> {
+        let rc = unsafe {
+            mdbm_sys::mdbm_lock_smart(self.db, &to_raw_datum(&key), flags as libc::c_int)
+        };
+        trace_op!(op = "lock", key_len = key.bytes.len() as u64, rc = rc);
+
+        if rc == 1 {
+            Ok(Lock {
+                db: self,
+                key,
+                write: flags != 0,
+            })
+        } else {
+            Err(self.last_lock_error("lock"))
+        }
+    }
+
+    /// Get the cache mode, or `None` if the database isn't used as a cache.
+    pub fn get_cache_mode(&self) -> Option<CacheMode> {
+        let mode = unsafe { mdbm_sys::mdbm_get_cachemode(self.db) };
+        CacheMode::from_mode(mode as u32)
+    }
+
+    /// Use the database as a cache, evicting entries by `mode` once it
+    /// reaches its size limit instead of failing stores.
+    ///
+    /// MDBM keeps no running eviction count; each eviction is reported to
+    /// the stats callback as `StatType::CacheEvict`.
+    pub fn set_cache_mode(&self, mode: CacheMode) -> Result<(), io::Error> {
+        let rc = unsafe { mdbm_sys::mdbm_set_cachemode(self.db, mode.mode() as libc::c_int) };
+        if rc != 0 {
+            return Err(last_error("set_cachemode"));
+        }
+        Ok(())
+    }
+
+    /// Get the value alignment of the database.
+    pub fn get_alignment(&self) -> Option<Alignment> {
+        let mask = unsafe { mdbm_sys::mdbm_get_alignment(self.db) };
+        Alignment::from_mask(mask as u32)
+    }
+
+    /// Set the value alignment. MDBM only allows this on an empty database.
+    pub fn set_alignment(&self, alignment: Alignment) -> Result<(), io::Error> {
+        let rc = unsafe { mdbm_sys::mdbm_set_alignment(self.db, alignment.mask() as libc::c_int) };
+        if rc != 0 {
+            return Err(last_error("set_alignment"));
+        }
+        Ok(())
+    }
+
+    /// Gather the database's effective settings in one place, for logging or
+    /// checking a file opened by someone else.
+    pub fn config(&self) -> DbConfig {
+        let page_size = unsafe { mdbm_sys::mdbm_get_page_size(self.db) };
+        let limit_size = unsafe { mdbm_sys::mdbm_get_limit_size(self.db) };
+        DbConfig {
+            page_size: page_size.max(0) as u32,
+            hash: self.get_hash(),
+            alignment: self.get_alignment(),
+            large_objects: self.large_objects,
+            cache_mode: self.get_cache_mode(),
+            limit_size: if limit_size > 0 {
+                Some(limit_size as u64)
+            } else {
+                None
+            },
+        }
+    }
+
+    /// Preallocate room for a burst of `additional_entries` values of roughly
+    /// `avg_value_size` bytes, like `Vec::reserve`.
+    ///
+    /// This pre-splits the database to the number of pages needed (rounded up
+    /// to a power of two, as MDBM grows by doubling), raising the size limit
+    /// first if one is set and too small. The raised limit stays in place for
+    /// the life of the file. MDBM only pre-splits databases that hold no data
+    /// yet, so call this right after creating the file.
+    ///
+    /// Fails with `InvalidInput` if the reservation doesn't fit in MDBM's page
+    /// count.
+    pub fn reserve(&self, additional_entries: u32, avg_value_size: usize) -> Result<(), io::Error> {
+        // Rough per-entry cost of MDBM's slot header plus a short key.
+        const ENTRY_OVERHEAD: usize = 32;
+
+        let page_size = unsafe { mdbm_sys::mdbm_get_page_size(self.db) };
+        if page_size <= 0 {
+            return Err(last_error("get_page_size"));
+        }
+        let page_size = page_size as usize;
+
+        let too_large = || {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "cannot reserve {} entries of {} bytes",
+                    additional_entries, avg_value_size
+                ),
+            )
+        };
+        let bytes = avg_value_size
+            .checked_add(ENTRY_OVERHEAD)
+            .and_then(|entry| entry.checked_mul(additional_entries as usize))
+            .ok_or_else(too_large)?;
+        let pages = bytes
+            .div_ceil(page_size)
+            .max(1)
+            .checked_next_power_of_two()
+            .ok_or_else(too_large)?;
+        let total = pages.checked_mul(page_size).ok_or_else(too_large)?;
+        let pages = mdbm_sys::mdbm_ubig_t::try_from(pages).map_err(|_| too_large())?;
+
+        unsafe {
+            let limit = mdbm_sys::mdbm_get_limit_size(self.db);
+            if limit > 0 && limit < total as u64 {
+                let rc = mdbm_sys::mdbm_limit_size_v3(self.db, pages, None, ptr::null_mut());
+                if rc != 0 {
+                    return Err(last_error("limit_size"));
+                }
+            }
+
+            if mdbm_sys::mdbm_pre_split(self.db, pages) != 0 {
+                return Err(last_error("pre_split"));
+            }
+        }
+        Ok(())
+    }
+
+    /// Get the number of the page that holds `key`.
+    pub fn page_for_key<'k, K>(&self, key: &'k K) -> Result<u32, io::Error>
+    where
+        K: AsDatum<'k> + ?Sized,
+    {
+        self.page_for_datum(&key.as_datum())
+    }
+
+    fn page_for_datum(&self, key: &Datum) -> Result<u32, io::Error> {
+        let page = unsafe { mdbm_sys::mdbm_get_page(self.db, &to_raw_datum(key)) };
+        if page == mdbm_sys::mdbm_ubig_t::MAX {
+            return Err(last_error("get_page"));
+        }
+        Ok(page as u32)
+    }
+
+    /// Iterate over entries grouped by the page they live on, in page order.
+    ///
+    /// All entries are read up front to bucket them, so this holds the whole
+    /// database in memory. Entries whose page MDBM can't report are left out
+    /// and counted in `PageIter::unplaced`.
+    pub fn iter_pages(&self) -> PageIter {
+        let mut pages = BTreeMap::new();
+        let mut unplaced = 0;
+        for (key, value) in self.iter_with_values() {
+            let page =
+                unsafe { mdbm_sys::mdbm_get_page(self.db, &to_raw_datum(&Datum::new(&key))) };
+            if page == mdbm_sys::mdbm_ubig_t::MAX {
+                unplaced += 1;
+                continue;
+            }
+            pages
+                .entry(page as u32)
+                .or_insert_with(Vec::new)
+                .push((key, value));
+        }
+        PageIter {
+            pages: pages.into_iter(),
+            unplaced,
+        }
+    }
+
+    /// A short, human-readable summary of the database's size and how full
+    /// its pages are, for logs and dashboards.
+    ///
+    /// A page's use is the bytes of its keys and values over the page size,
+    /// ignoring MDBM's per-entry overhead. Like `iter_pages`, this reads the
+    /// whole database into memory.
+    pub fn report(&self) -> String {
+        let page_size = unsafe { mdbm_sys::mdbm_get_page_size(self.db) }.max(1) as f64;
+        let mut records = 0;
+        // (share of the page used, page number) of the emptiest and fullest
+        // pages holding any entries.
+        let mut least: Option<(f64, u32)> = None;
+        let mut most: Option<(f64, u32)> = None;
+        let pages = self.iter_pages();
+        records += pages.unplaced();
+        for (page, entries) in pages {
+            records += entries.len();
+            let used: usize = entries.iter().map(|(k, v)| k.len() + v.len()).sum();
+            let share = used as f64 / page_size;
+            if least.is_none_or(|(l, _)| share < l) {
+                least = Some((share, page));
+            }
+            if most.is_none_or(|(m, _)| share > m) {
+                most = Some((share, page));
+            }
+        }
+
+        let mut report = format!(
+            "size: {} bytes\npages: {}\nrecords: {}\nfill ratio: {:.2}\n",
+            self.memory_usage().mapped_bytes,
+            self.num_pages(),
+            records,
+            self.fill_ratio()
+        );
+        if let (Some((l, lp)), Some((m, mp))) = (least, most) {
+            report.push_str(&format!(
+                "least-filled page: {} ({:.0}% used)\nmost-filled page: {} ({:.0}% used)\n",
+                lp,
+                l * 100.0,
+                mp,
+                m * 100.0
+            ));
+        }
+        report
+    }
+
+    /// Count values by size.
+    ///
+    /// `buckets` holds ascending, inclusive upper bounds in bytes. A value is
+    /// counted in the first bucket whose bound it fits under; the returned
+    /// vector has one extra trailing count for values larger than every bound.
+    pub fn value_size_histogram(&self, buckets: &[usize]) -> Vec<u64> {
+        let mut counts = vec![0; buckets.len() + 1];
+        for (_, value) in self.iter_with_values() {
+            let i = buckets
+                .iter()
+                .position(|&bound| value.len() <= bound)
+                .unwrap_or(buckets.len());
+            counts[i] += 1;
+        }
+        counts
+    }
+
+    /// Start counting fetches, stores and deletes for `op_counter`.
+    pub fn enable_stat_operations(&self) -> Result<(), io::Error> {
+        let rc = unsafe {
+            mdbm_sys::mdbm_enable_stat_operations(
+                self.db,
+                mdbm_sys::MDBM_STATS_BASIC as libc::c_int,
+            )
+        };
+        if rc != 0 {
+            return Err(last_error("enable_stat_operations"));
+        }
+        Ok(())
+    }
+
+    /// Start recording when the database was last fetched from, for
+    /// `last_access`, as well as counting operations for `op_counter`.
+    pub fn enable_access_time(&self) -> Result<(), io::Error> {
+        let rc = unsafe {
+            mdbm_sys::mdbm_enable_stat_operations(
+                self.db,
+                (mdbm_sys::MDBM_STATS_BASIC | mdbm_sys::MDBM_STATS_TIMED) as libc::c_int,
+            )
+        };
+        if rc != 0 {
+            return Err(last_error("enable_stat_operations"));
+        }
+        Ok(())
+    }
+
+    /// When any key was last fetched, to the second.
+    ///
+    /// MDBM keeps this time for the database as a whole, not per entry.
+    /// Returns `None` until `enable_access_time` and a fetch, or if MDBM
+    /// can't report it.
+    pub fn last_access(&self) -> Option<SystemTime> {
+        let mut secs: libc::time_t = 0;
+        let rc = unsafe {
+            mdbm_sys::mdbm_get_stat_time(self.db, StatCounter::Fetch.stat_type(), &mut secs)
+        };
+        if rc != 0 || secs <= 0 {
+            return None;
+        }
+        Some(UNIX_EPOCH + Duration::from_secs(secs as u64))
+    }
+
+    /// Have MDBM call `callback` for each statistics event on this handle.
+    ///
+    /// Replaces any callback set before. The callback lives as long as the
+    /// handle, or until `clear_stats_callback`.
+    pub fn set_stats_callback<F>(&mut self, callback: F) -> Result<(), io::Error>
+    where
+        F: FnMut(StatType, u64) + 'static,
+    {
+        let mut callback: Box<StatsCallback> = Box::new(Box::new(callback));
+        let user = &mut *callback as *mut StatsCallback as *mut libc::c_void;
+        let rc = unsafe {
+            mdbm_sys::mdbm_set_stats_func(
+                self.db,
+                mdbm_sys::MDBM_STATS_BASIC as libc::c_int,
+                Some(stats_trampoline),
+                user,
+            )
+        };
+        if rc != 0 {
+            return Err(last_error("set_stats_func"));
+        }
+        self.stats_callback = Some(callback);
+        Ok(())
+    }
+
+    /// Stop calling the stats callback, and drop it.
+    pub fn clear_stats_callback(&mut self) -> Result<(), io::Error> {
+        let rc = unsafe { mdbm_sys::mdbm_set_stats_func(self.db, 0, None, ptr::null_mut()) };
+        if rc != 0 {
+            return Err(last_error("set_stats_func"));
+        }
+        self.stats_callback = None;
+        Ok(())
+    }
+
+    /// Get one of MDBM's operation counters.
+    ///
+    /// Counters only move after `enable_stat_operations`; until then, or if
+    /// MDBM can't read the counter, this returns 0.
+    pub fn op_counter(&self, which: StatCounter) -> u64 {
+        let mut value: mdbm_sys::mdbm_counter_t = 0;
+        let rc = unsafe { mdbm_sys::mdbm_get_stat_counter(self.db, which.stat_type(), &mut value) };
+        if rc != 0 {
+            return 0;
+        }
+        value as u64
+    }
+
+    /// Report how much of the database is mapped, and how much of that is
+    /// resident in RAM.
+    ///
+    /// Residency comes from `mincore` over this process's mappings of the
+    /// file, found in `/proc/self/maps`. Where that can't be read, resident
+    /// bytes are reported as 0.
+    pub fn memory_usage(&self) -> MemUsage {
+        let mapped_bytes = unsafe { mdbm_sys::mdbm_get_size(self.db) };
+        let resident_bytes = self.resident_bytes().unwrap_or(0).min(mapped_bytes);
+        MemUsage {
+            mapped_bytes,
+            resident_bytes,
+        }
+    }
+
+    fn resident_bytes(&self) -> Result<u64, io::Error> {
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+        let mut resident = 0;
+        for (start, len, _) in self.mapped_ranges()? {
+            let mut pages = vec![0u8; len.div_ceil(page_size)];
+            let rc = unsafe { libc::mincore(start as *mut libc::c_void, len, pages.as_mut_ptr()) };
+            if rc != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            resident += pages.iter().filter(|&&p| p & 1 != 0).count() * page_size;
+        }
+        Ok(resident as u64)
+    }
+
+    /// The `(address, length, file offset)` of each of this process's
+    /// shared mappings of the database file, found in `/proc/self/maps`.
+    fn mapped_ranges(&self) -> Result<Vec<(usize, usize, u64)>, io::Error> {
+        let (dev, inode) = self.file_id()?;
+        let maps = fs::read_to_string("/proc/self/maps")?;
+        Ok(maps
+            .lines()
+            .filter_map(|line| parse_shared_mapping(line, dev, inode))
+            .collect())
+    }
+
+    /// Ask the kernel to start reading MDBM page `page_num` into memory, so
+    /// a scan doesn't stall on it later.
+    ///
+    /// This is `madvise(MADV_WILLNEED)` over the page's place in this
+    /// process's mapping of the file. It returns immediately; page numbers
+    /// come from `page_for_key` or `iter_pages`. A page outside the mapping
+    /// is an `InvalidInput` error.
+    pub fn prefetch_page(&self, page_num: u32) -> Result<(), io::Error> {
+        let page_size = unsafe { mdbm_sys::mdbm_get_page_size(self.db) };
+        if page_size <= 0 {
+            return Err(last_error("get_page_size"));
+        }
+        let offset = page_num as u64 * page_size as u64;
+        for (start, len, map_offset) in self.mapped_ranges()? {
+            if offset < map_offset || offset >= map_offset + len as u64 {
+                continue;
+            }
+            let addr = start + (offset - map_offset) as usize;
+            let end = (addr + page_size as usize).min(start + len);
+            return advise(addr, end - addr, libc::MADV_WILLNEED);
+        }
+        Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("page {} is not mapped", page_num),
+        ))
+    }
+
+    /// Let the kernel drop this process's resident pages of the database,
+    /// such as after a one-off full scan.
+    ///
+    /// This is `madvise(MADV_DONTNEED)` over every shared mapping of the
+    /// file, which is how MDBM maps it, so nothing is lost: later accesses
+    /// fault the data back in from the page cache or disk.
+    pub fn release_cached_pages(&self) -> Result<(), io::Error> {
+        for (start, len, _) in self.mapped_ranges()? {
+            advise(start, len, libc::MADV_DONTNEED)?;
+        }
+        Ok(())
+    }
+
+    /// The share of allocated page bytes holding data, from 0 to 1.
+    ///
+    /// Returns 0 if MDBM can't report its stats.
+    pub fn fill_ratio(&self) -> f64 {
+        self.get_stats()
+            .ok()
+            .and_then(|stats| fill_ratio(&stats))
+            .unwrap_or(0.0)
+    }
+
+    /// The deepest level of MDBM's page directory, which grows by one each
+    /// time the database doubles its page count.
+    ///
+    /// Returns 0 if MDBM can't report its stats.
+    pub fn directory_depth(&self) -> u32 {
+        self.get_stats().map(|stats| stats.s_max_level).unwrap_or(0)
+    }
+
+    /// The number of data pages in use.
+    ///
+    /// Returns 0 if MDBM can't report its database info.
+    pub fn num_pages(&self) -> u32 {
+        self.get_db_info()
+            .map(|info| info.db_num_pages)
+            .unwrap_or(0)
+    }
+
+    /// The number of pages holding MDBM's page directory.
+    ///
+    /// Returns 0 if MDBM can't report its database info.
+    pub fn num_dir_pages(&self) -> u32 {
+        self.get_db_info()
+            .map(|info| info.db_num_dir_pages)
+            .unwrap_or(0)
+    }
+
+    fn get_db_info(&self) -> Result<mdbm_sys::mdbm_db_info_t, io::Error> {
+        unsafe {
+            let mut info: mdbm_sys::mdbm_db_info_t = mem::zeroed();
+            if mdbm_sys::mdbm_get_db_info(self.db, &mut info) != 0 {
+                return Err(last_error("get_db_info"));
+            }
+            Ok(info)
+        }
+    }
+
+    /// Set the window size of a database opened with `open_windowed`.
+    pub fn set_window_size(&self, size: usize) -> Result<(), io::Error> {
+        if unsafe { mdbm_sys::mdbm_set_window_size(self.db, size) } != 0 {
+            return Err(last_error("set_window_size"));
+        }
+        Ok(())
+    }
+
+    /// How the window of a database opened with `open_windowed` is being
+    /// used.
+    pub fn window_stats(&self) -> Result<WindowStats, io::Error> {
+        let stats = unsafe {
+            let mut stats: mdbm_sys::mdbm_window_stats_t = mem::zeroed();
+            let size = mem::size_of::<mdbm_sys::mdbm_window_stats_t>();
+            if mdbm_sys::mdbm_get_window_stats(self.db, &mut stats, size) != 0 {
+                return Err(last_error("get_window_stats"));
+            }
+            stats
+        };
+        Ok(WindowStats {
+            reused: stats.w_num_reused,
+            remapped: stats.w_num_remapped,
+            window_size: stats.w_window_size,
+            max_window_used: stats.w_max_window_used,
+        })
+    }
+
+    /// Set the window to `initial` bytes, then grow it by `growth_factor`,
+    /// up to `max` bytes, whenever it thrashes.
+    ///
+    /// Only `fetch` checks for thrashing: every so many window accesses, by
+    /// any call, the next `fetch` counts the window as thrashing if more of
+    /// them remapped a page than reused one. Other reads never grow the
+    /// window on their own. If MDBM refuses a larger window, growth stops
+    /// there.
+    pub fn set_window_auto_grow(
+        &self,
+        initial: usize,
+        max: usize,
+        growth_factor: f64,
+    ) -> Result<(), io::Error> {
+        if initial > max || growth_factor.is_nan() || growth_factor <= 1.0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "window growth needs initial <= max and a growth factor above 1",
+            ));
+        }
+        self.set_window_size(initial)?;
+        let stats = self.window_stats()?;
+        self.window_growth.set(Some(WindowGrowth {
+            size: initial,
+            max,
+            factor: growth_factor,
+            reused: stats.reused,
+            remapped: stats.remapped,
+        }));
+        Ok(())
+    }
+
+    /// Apply the `set_window_auto_grow` policy, if any.
+    fn grow_window_if_thrashing(&self) {
+        let mut growth = match self.window_growth.get() {
+            Some(growth) => growth,
+            None => return,
+        };
+        let stats = match self.window_stats() {
+            Ok(stats) => stats,
+            Err(_) => return,
+        };
+        let reused = stats.reused.saturating_sub(growth.reused);
+        let remapped = stats.remapped.saturating_sub(growth.remapped);
+        if reused + remapped < WINDOW_CHECK_INTERVAL {
+            return;
+        }
+        let mut stats = Ok(stats);
+        if remapped > reused {
+            let target = ((growth.size as f64 * growth.factor) as usize).min(growth.max);
+            if target > growth.size && self.set_window_size(target).is_ok() {
+                growth.size = target;
+                // Resizing may reset the counters.
+                stats = self.window_stats();
+            } else {
+                growth.max = growth.size;
+            }
+        }
+        match stats {
+            Ok(stats) if growth.size < growth.max => {
+                growth.reused = stats.reused;
+                growth.remapped = stats.remapped;
+                self.window_growth.set(Some(growth));
+            }
+            _ => self.window_growth.set(None),
+        }
+    }
+
+    /// Compact the database, but only if it has become sparse.
+    ///
+    /// The free ratio is the share of allocated page bytes not holding data.
+    /// If it exceeds `min_free_ratio`, this runs `mdbm_compress_tree` and
+    /// returns `true`; otherwise it does nothing and returns `false`.
+    pub fn shrink_to_fit(&self, min_free_ratio: f64) -> Result<bool, io::Error> {
+        let free_ratio = match fill_ratio(&self.get_stats()?) {
+            Some(fill) => 1.0 - fill,
+            None => return Ok(false),
+        };
+        if free_ratio <= min_free_ratio {
+            return Ok(false);
+        }
+
+        let _lock = self.lock_db()?;
+        unsafe { mdbm_sys::mdbm_compress_tree(self.db) };
+        Ok(true)
+    }
+
+    fn get_stats(&self) -> Result<mdbm_sys::mdbm_stats_t, io::Error> {
+        unsafe {
+            let mut stats: mdbm_sys::mdbm_stats_t = mem::zeroed();
+            let rc = mdbm_sys::mdbm_get_stats(
+                self.db,
+                &mut stats,
+                mem::size_of::<mdbm_sys::mdbm_stats_t>(),
+            );
+            if rc != 0 {
+                return Err(last_error("get_stats"));
+            }
+            Ok(stats)
+        }
+    }
+
+    /// Write every entry to `w` as a `key\tvalue` line.
+    ///
+    /// Entries come out in hash order; sort the lines before diffing two
+    /// databases.
+    pub fn dump_to<W: io::Write>(
+        &self,
+        w: &mut W,
+        encoding: DumpEncoding,
+    ) -> Result<(), io::Error> {
+        let mut line = String::new();
+        for (key, value) in self.iter_with_values() {
+            line.clear();
+            encoding.encode(&key, &mut line);
+            line.push('\t');
+            encoding.encode(&value, &mut line);
+            line.push('\n');
+            w.write_all(line.as_bytes())?;
+        }
+        Ok(())
+    }
+
+    /// Store each `key\tvalue` line read from `r`, as written by `dump_to`.
+    ///
+    /// Returns the number of entries stored; with `StoreMode::Insert`, keys
+    /// that already exist are skipped and not counted. A malformed line fails
+    /// with `InvalidData` naming its line number, leaving earlier lines
+    /// stored.
+    pub fn load_from<R: io::BufRead>(
+        &self,
+        r: &mut R,
+        encoding: DumpEncoding,
+        mode: StoreMode,
+    ) -> Result<u64, io::Error> {
+        let mut loaded = 0;
+        for (i, line) in <&mut R as io::BufRead>::lines(r).enumerate() {
+            let line = line?;
+            let malformed = |msg: String| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("line {}: {}", i + 1, msg),
+                )
+            };
+            let (key, value) = match line.split_once('\t') {
+                Some(pair) => pair,
+                None => {
+                    return Err(malformed(
+                        "expected a tab between key and value".to_string(),
+                    ))
+                }
+            };
+            let key = encoding.decode(key).map_err(&malformed)?;
+            let value = encoding.decode(value).map_err(&malformed)?;
+            if self.store_datum(&Datum::new(&key), &Datum::new(&value), mode.flag())? == 0 {
+                loaded += 1;
+            }
+        }
+        Ok(loaded)
+    }
+
+    /// Iterate over every entry, yielding owned `(key, value)` pairs.
+    ///
+    /// Each step reads the `kvpair` returned by `mdbm_first_r`/`mdbm_next_r`,
+    /// so values come back alongside their keys without a second fetch.
+    pub fn iter_with_values<'a>(&'a self) -> Entries<'a> {
+        Entries {
+            cursor: self.cursor(),
+        }
+    }
+
+    /// Iterate over entries whose value carries a sequence number above
+    /// `watermark`.
+    ///
+    /// MDBM has no notion of insertion order, so `seq_of` extracts the
+    /// sequence number from a value; values it returns `None` for are
+    /// skipped. This still scans every entry.
+    pub fn iter_since<'a, F>(
+        &'a self,
+        watermark: u64,
+        seq_of: F,
+    ) -> impl Iterator<Item = (Vec<u8>, Vec<u8>)> + 'a
+    where
+        F: Fn(&[u8]) -> Option<u64> + 'a,
+    {
+        self.iter_with_values()
+            .filter(move |(_, value)| matches!(seq_of(value), Some(seq) if seq > watermark))
+    }
+
+    /// Collect every entry with an 8-byte key, decoded as a big-endian `u64`,
+    /// sorted by that integer.
+    ///
+    /// MDBM can't iterate in key order, so this reads every entry into
+    /// memory and sorts it; keep it for databases that fit comfortably.
+    /// Entries whose key isn't 8 bytes long are skipped.
+    pub fn iter_sorted_be_u64(&self) -> Vec<(u64, Vec<u8>)> {
+        let mut entries: Vec<(u64, Vec<u8>)> = self
+            .iter_with_values()
+            .filter(|(key, _)| key.len() == 8)
+            .map(|(key, value)| {
+                let mut bytes = [0u8; 8];
+                bytes.copy_from_slice(&key);
+                (u64::from_be_bytes(bytes), value)
+            })
+            .collect();
+        entries.sort_by_key(|&(key, _)| key);
+        entries
+    }
+
+    /// Start a resumable walk over every entry.
+    pub fn cursor<'a>(&'a self) -> Cursor<'a> {
+        Cursor {
+            db: self,
+            iter: new_iter(),
+            state: IterState::Start,
+            pending: None,
+        }
+    }
+}
+
+/// Bulk-insert entries, replacing existing values, under a single database
+/// lock.
+///
+/// # Panics
+///
+/// Panics if the lock can't be taken or a store fails, since `Extend` has no
+/// way to report errors.
+impl<K, V> Extend<(K, V)> for MDBM
+where
+    K: for<'a> AsDatum<'a>,
+    V: for<'a> AsDatum<'a>,
+{
+    fn extend<I: IntoIterator<Item = (K, V)>>(&mut self, iter: I) {
+        let _lock = self.lock_db().expect("failed to lock database");
+        for (key, value) in iter {
+            self.set(&key, &value, mdbm_sys::MDBM_REPLACE as isize)
+                .expect("failed to store entry");
+        }
+    }
+}
+
+impl Drop for MDBM {
+    /// Best-effort sync and close; use `close` to see errors.
+    fn drop(&mut self) {
+        if self.db.is_null() {
+            return;
+        }
+        unsafe {
+            mdbm_sys::mdbm_sync(self.db);
+            mdbm_sys::mdbm_close(self.db);
+        }
+    }
+}
+
+/// A database whose file is deleted when it drops, for tests and
+/// short-lived caches.
+///
+/// Dereferences to the `MDBM`. On drop the handle is closed first, then the
+/// file at its `path` is removed, ignoring errors.
+pub struct ScopedDb {
+    db: Option<MDBM>,
+}
+
+impl ScopedDb {
+    /// Take over `db`, deleting its file once done.
+    pub fn new(db: MDBM) -> ScopedDb {
+        ScopedDb { db: Some(db) }
+    }
+}
+
+impl Deref for ScopedDb {
+    type Target = MDBM;
+
+    fn deref(&self) -> &MDBM {
+        self.db.as_ref().expect("ScopedDb used after drop")
+    }
+}
+
+impl DerefMut for ScopedDb {
+    fn deref_mut(&mut self) -> &mut MDBM {
+        self.db.as_mut().expect("ScopedDb used after drop")
+    }
+}
+
+impl Drop for ScopedDb {
+    fn drop(&mut self) {
+        if let Some(db) = self.db.take() {
+            let path = db.path().to_path_buf();
+            drop(db);
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+/// Compares and orders by byte content, lexicographically.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Datum<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Datum<'a> {
+    pub fn new(bytes: &'a [u8]) -> Datum<'a> {
+        Datum { bytes: bytes }
+    }
+}
+
+/// Byte sources usable as keys and values.
+///
+/// Every common way of holding bytes works directly at the call site, with no
+/// manual slicing:
+///
+/// ```no_run
+/// # use rust_mdbm::MDBM;
+/// let db = MDBM::new("example.db", Default::default(), 0o644, 0, 0).unwrap();
+/// let vec = vec![1u8, 2, 3];
+/// let array = [1u8, 2, 3, 4];
+/// let string = String::from("string");
+///
+/// db.set(&"str", &"value", 0).unwrap();
+/// db.set(&vec[..], &"value", 0).unwrap();
+/// db.set(&vec, &"value", 0).unwrap();
+/// db.set(&array, &"value", 0).unwrap();
+/// db.set(b"byte string", &string, 0).unwrap();
+///
+/// let key = [1u8, 2, 3, 4];
+/// let _lock = db.lock(&key, 0).unwrap();
+/// ```
+pub trait AsDatum<'a> {
+    fn as_datum(&'a self) -> Datum<'a>;
+}
+
+impl<'a, 'b, T: AsDatum<'b> + ?Sized> AsDatum<'a> for &'b T {
+    fn as_datum(&'a self) -> Datum<'a> {
+        (**self).as_datum()
+    }
+}
+
+impl<'a> AsDatum<'a> for [u8] {
+    fn as_datum(&'a self) -> Datum<'a> {
+        Datum::new(self)
+    }
+}
 
 impl<'a> AsDatum<'a> for str {
     fn as_datum(&'a self) -> Datum<'a> {
         self.as_bytes().as_datum()
     }
-}
+}
+
+impl<'a> AsDatum<'a> for Vec<u8> {
+    fn as_datum(&'a self) -> Datum<'a> {
+        self[..].as_datum()
+    }
+}
+
+impl<'a> AsDatum<'a> for String {
+    fn as_datum(&'a self) -> Datum<'a> {
+        self.as_bytes().as_datum()
+    }
+}
+
+impl<'a, const N: usize> AsDatum<'a> for [u8; N] {
+    fn as_datum(&'a self) -> Datum<'a> {
+        self[..].as_datum()
+    }
+}
+
+/// The trailing nul is *not* part of the datum, so a `CStr` key matches the
+/// same key stored as a `str` or `[u8]`, as it would with C's `strcmp`.
+impl<'a> AsDatum<'a> for CStr {
+    fn as_datum(&'a self) -> Datum<'a> {
+        self.to_bytes().as_datum()
+    }
+}
+
+/// An owned copy of a value, as returned by `MDBM::fetch`.
+///
+/// Values of up to 22 bytes are kept inline, so small fetches don't
+/// allocate; larger ones go on the heap. Either way it dereferences to
+/// `[u8]`.
+#[derive(Clone)]
+pub struct OwnedDatum {
+    repr: OwnedRepr,
+}
+
+// The inline bytes and length fit in the space the Vec takes anyway.
+const INLINE_CAPACITY: usize = 22;
+
+#[derive(Clone)]
+enum OwnedRepr {
+    Inline { len: u8, buf: [u8; INLINE_CAPACITY] },
+    Heap(Vec<u8>),
+}
+
+impl OwnedDatum {
+    fn new(bytes: &[u8]) -> OwnedDatum {
+        let repr = if bytes.len() <= INLINE_CAPACITY {
+            let mut buf = [0u8; INLINE_CAPACITY];
+            buf[..bytes.len()].copy_from_slice(bytes);
+            OwnedRepr::Inline {
+                len: bytes.len() as u8,
+                buf,
+            }
+        } else {
+            OwnedRepr::Heap(bytes.to_vec())
+        };
+        OwnedDatum { repr }
+    }
+
+    /// Convert into a `Vec`, allocating only if the value was inline.
+    pub fn into_vec(self) -> Vec<u8> {
+        match self.repr {
+            OwnedRepr::Inline { len, buf } => buf[..len as usize].to_vec(),
+            OwnedRepr::Heap(vec) => vec,
+        }
+    }
+}
+
+impl Deref for OwnedDatum {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self.repr {
+            OwnedRepr::Inline { len, ref buf } => &buf[..len as usize],
+            OwnedRepr::Heap(ref vec) => vec,
+        }
+    }
+}
+
+impl AsRef<[u8]> for OwnedDatum {
+    fn as_ref(&self) -> &[u8] {
+        self
+    }
+}
+
+impl PartialEq for OwnedDatum {
+    fn eq(&self, other: &OwnedDatum) -> bool {
+        **self == **other
+    }
+}
+
+impl Eq for OwnedDatum {}
+
+impl fmt::Debug for OwnedDatum {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+/// A signed integer key whose bytes sort in numeric order.
+///
+/// The value is stored big-endian with the sign bit flipped, so comparing the
+/// encoded bytes lexicographically puts negative numbers before positive
+/// ones. The encoded bytes are kept, rather than the `i64`, so that
+/// `as_datum` can borrow them.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct OrdI64([u8; 8]);
+
+impl OrdI64 {
+    pub fn new(value: i64) -> OrdI64 {
+        OrdI64(((value as u64) ^ (1 << 63)).to_be_bytes())
+    }
+
+    /// The integer this key encodes.
+    pub fn get(&self) -> i64 {
+        (u64::from_be_bytes(self.0) ^ (1 << 63)) as i64
+    }
+
+    /// Decode bytes produced by `OrdI64`, or `None` if they aren't 8 bytes.
+    pub fn decode(bytes: &[u8]) -> Option<i64> {
+        let mut buf = [0; 8];
+        if bytes.len() != buf.len() {
+            return None;
+        }
+        buf.copy_from_slice(bytes);
+        Some(OrdI64(buf).get())
+    }
+}
+
+impl From<i64> for OrdI64 {
+    fn from(value: i64) -> OrdI64 {
+        OrdI64::new(value)
+    }
+}
+
+impl<'a> AsDatum<'a> for OrdI64 {
+    fn as_datum(&'a self) -> Datum<'a> {
+        self.0.as_datum()
+    }
+}
+
+fn to_raw_datum(datum: &Datum) -> mdbm_sys::datum {
+    mdbm_sys::datum {
+        dptr: datum.bytes.as_ptr() as *mut _,
+        dsize: datum.bytes.len() as libc::c_int,
+    }
+}
+
+/// `bytes_used / capacity`, or `None` for a database with no pages.
+fn fill_ratio(stats: &mdbm_sys::mdbm_stats_t) -> Option<f64> {
+    let capacity = stats.s_page_count as f64 * stats.s_page_size as f64;
+    if capacity == 0.0 {
+        return None;
+    }
+    Some(stats.s_bytes_used as f64 / capacity)
+}
+
+/// Reject a `psize` that `mdbm_open` can't use.
+fn check_page_size(psize: usize) -> Result<(), io::Error> {
+    let (min, max) = (
+        mdbm_sys::MDBM_MINPAGE as usize,
+        mdbm_sys::MDBM_MAXPAGE as usize,
+    );
+    if psize == 0 || (psize.is_power_of_two() && psize >= min && psize <= max) {
+        return Ok(());
+    }
+    Err(io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!(
+            "page size {} must be a power of two between {} and {} bytes, or 0 for the default",
+            psize, min, max
+        ),
+    ))
+}
+
+/// `madvise` the range `[addr, addr + len)`, widened to whole OS pages.
+fn advise(addr: usize, len: usize, advice: libc::c_int) -> Result<(), io::Error> {
+    let os_page = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+    let start = addr - addr % os_page;
+    let rc = unsafe { libc::madvise(start as *mut libc::c_void, addr + len - start, advice) };
+    if rc != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// The `presize` to open with for a database of at least `bytes`: that many
+/// bytes rounded up to whole pages of `psize`, or MDBM's default page size if
+/// `psize` is 0.
+fn presize_for_bytes(bytes: u64, psize: usize) -> Result<usize, io::Error> {
+    let page = if psize == 0 {
+        mdbm_sys::MDBM_PAGESIZ as u64
+    } else {
+        psize as u64
+    };
+    bytes
+        .checked_next_multiple_of(page)
+        .filter(|&presize| presize <= libc::c_int::MAX as u64)
+        .map(|presize| presize as usize)
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("presize of {} bytes is too large", bytes),
+            )
+        })
+}
+
+/// An upper bound on the room an entry takes on a page besides its key and
+/// value bytes: the page header, the entry's index slot and alignment.
+const PAGE_OVERHEAD: usize = 64;
+
+/// Flags for `mdbm_lock_smart` that take an exclusive (write) lock.
+const WRITE_LOCK: isize = mdbm_sys::MDBM_O_RDWR as isize;
+
+/// Run `op`, retrying with exponential backoff while it fails with an errno
+/// that `is_transient` accepts.
+fn retry_transient<T, F>(retries: u32, backoff: Duration, mut op: F) -> Result<T, io::Error>
+where
+    F: FnMut() -> Result<T, io::Error>,
+{
+    let mut delay = backoff;
+    let mut attempt = 0;
+    loop {
+        match op() {
+            Err(ref e) if attempt < retries && is_transient(e) => {
+                thread::sleep(delay);
+                delay *= 2;
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+fn is_transient(err: &io::Error) -> bool {
+    let errno = match MdbmError::from_io(err) {
+        Some(e) => Some(e.errno()),
+        None => err.raw_os_error(),
+    };
+    matches!(
+        errno,
+        Some(libc::EAGAIN) | Some(libc::EINTR) | Some(libc::EBUSY)
+    )
+}
+
+/// The `(address, length, file offset)` of a `/proc/self/maps` line, if it
+/// is a shared mapping of the file `dev` and `inode` identify.
+///
+/// Both must match, since inodes repeat across filesystems, and private
+/// mappings are skipped: advice like `MADV_DONTNEED` would throw away their
+/// changes.
+fn parse_shared_mapping(line: &str, dev: u64, inode: u64) -> Option<(usize, usize, u64)> {
+    // start-end perms offset maj:min inode [path]
+    let fields: Vec<&str> = line.split_whitespace().collect();
+    if fields.len() < 5 || !fields[1].ends_with('s') {
+        return None;
+    }
+    if fields[4].parse::<u64>().ok() != Some(inode) {
+        return None;
+    }
+    let mut dev_fields = fields[3].split(':').map(|n| u64::from_str_radix(n, 16));
+    match (dev_fields.next(), dev_fields.next()) {
+        (Some(Ok(major)), Some(Ok(minor))) if (major, minor) == dev_numbers(dev) => {}
+        _ => return None,
+    }
+    let mut bounds = fields[0].split('-').map(|a| usize::from_str_radix(a, 16));
+    let offset = u64::from_str_radix(fields[2], 16).ok()?;
+    match (bounds.next(), bounds.next()) {
+        (Some(Ok(start)), Some(Ok(end))) => Some((start, end - start, offset)),
+        _ => None,
+    }
+}
+
+/// Split a Linux `st_dev` into its major and minor numbers.
+fn dev_numbers(dev: u64) -> (u64, u64) {
+    let major = ((dev >> 8) & 0xfff) | ((dev >> 32) & 0xffff_f000);
+    let minor = (dev & 0xff) | ((dev >> 12) & 0xffff_ff00);
+    (major, minor)
+}
+
+/// Length of the expiry prefix written by `MDBM::store_with_ttl`.
+const TTL_PREFIX_LEN: usize = 8;
+
+/// The expiry time at the front of a value written by `store_with_ttl`.
+fn ttl_expiry(value: &[u8]) -> Option<u64> {
+    if value.len() < TTL_PREFIX_LEN {
+        return None;
+    }
+    let mut bytes = [0u8; TTL_PREFIX_LEN];
+    bytes.copy_from_slice(&value[..TTL_PREFIX_LEN]);
+    Some(u64::from_be_bytes(bytes))
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+/// Run `f` on a new database in the temporary directory, then delete the
+/// file, even if `f` panics.
+///
+/// Meant for tests: it panics if the database can't be created. Available
+/// to other crates with the `test-util` feature.
+#[cfg(any(test, feature = "test-util"))]
+pub fn with_temp_db<R, F: FnOnce(&MDBM) -> R>(options: Options, f: F) -> R {
+    let options = Options {
+        create: true,
+        ..options
+    };
+    let db = MDBM::new(temp_db_path(), options, 0o600, 0, 0)
+        .expect("failed to create a temporary database");
+    let db = ScopedDb::new(db);
+    f(&db)
+}
+
+/// A small xorshift generator for `MDBM::sample`. Not cryptographic.
+struct XorShift(u64);
+
+impl XorShift {
+    /// Seed from the standard library's per-process random hash keys.
+    fn new() -> XorShift {
+        let seed = RandomState::new().build_hasher().finish();
+        XorShift(seed | 1)
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    /// A number in `0..bound`, with a bias too small to matter here.
+    fn below(&mut self, bound: u64) -> u64 {
+        self.next() % bound
+    }
+}
+
+/// A fresh path in the temporary directory for a scratch database.
+fn temp_db_path() -> PathBuf {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.subsec_nanos())
+        .unwrap_or(0);
+    env::temp_dir().join(format!(
+        "rust-mdbm-{}-{}-{}.db",
+        process::id(),
+        COUNTER.fetch_add(1, Ordering::Relaxed),
+        nanos
+    ))
+}
+
+/// View the bytes behind a datum returned by MDBM.
+///
+/// The caller picks the lifetime, so it must not outlive whatever keeps the
+/// underlying page mapped and unchanged.
+unsafe fn from_raw_datum<'a>(datum: &mdbm_sys::datum) -> &'a [u8] {
+    if datum.dptr.is_null() {
+        return &[];
+    }
+    slice::from_raw_parts(datum.dptr as *const u8, datum.dsize as usize)
+}
+
+/// The equivalent of the `MDBM_ITER_INIT` macro.
+fn new_iter() -> mdbm_sys::MDBM_ITER {
+    mdbm_sys::MDBM_ITER {
+        m_pageno: 0,
+        m_next: -1,
+    }
+}
+
+#[derive(Copy, Clone, PartialEq)]
+enum IterState {
+    Start,
+    Running,
+    Done,
+}
+
+/// The `(key, value)` pairs found on one page.
+type PageEntries = Vec<(Vec<u8>, Vec<u8>)>;
+
+/// Copied `(key, value)` pairs, from `MDBM::find` and `MDBM::sample`.
+type OwnedEntries = Vec<(Vec<u8>, Vec<u8>)>;
+
+/// Iterator over entries grouped by page, created by `MDBM::iter_pages`.
+pub struct PageIter {
+    pages: btree_map::IntoIter<u32, PageEntries>,
+    unplaced: usize,
+}
+
+impl PageIter {
+    /// The number of entries left out because their page couldn't be found.
+    pub fn unplaced(&self) -> usize {
+        self.unplaced
+    }
+}
+
+impl Iterator for PageIter {
+    type Item = (u32, PageEntries);
+
+    fn next(&mut self) -> Option<(u32, PageEntries)> {
+        self.pages.next()
+    }
+}
+
+/// Iterator over all `(key, value)` pairs, created by `MDBM::iter_with_values`.
+pub struct Entries<'a> {
+    cursor: Cursor<'a>,
+}
+
+impl<'a> Iterator for Entries<'a> {
+    type Item = (Vec<u8>, Vec<u8>);
+
+    fn next(&mut self) -> Option<(Vec<u8>, Vec<u8>)> {
+        self.cursor.next_entry()
+    }
+}
+
+/// A resumable position in a walk over all entries, created by
+/// `MDBM::cursor`.
+///
+/// Entries come out in MDBM's hash order, not key order.
+pub struct Cursor<'a> {
+    db: &'a MDBM,
+    iter: mdbm_sys::MDBM_ITER,
+    state: IterState,
+    // An entry found by `seek`, returned by the next `next_entry`.
+    pending: Option<(Vec<u8>, Vec<u8>)>,
+}
+
+impl<'a> Cursor<'a> {
+    /// Get the next entry, or `None` once every entry has been seen.
+    pub fn next_entry(&mut self) -> Option<(Vec<u8>, Vec<u8>)> {
+        if let Some(entry) = self.pending.take() {
+            return Some(entry);
+        }
+
+        let kv = unsafe {
+            match self.state {
+                IterState::Start => mdbm_sys::mdbm_first_r(self.db.db, &mut self.iter),
+                IterState::Running => mdbm_sys::mdbm_next_r(self.db.db, &mut self.iter),
+                IterState::Done => return None,
+            }
+        };
+
+        if kv.key.dptr.is_null() {
+            self.state = IterState::Done;
+            return None;
+        }
+        self.state = IterState::Running;
+
+        // Copy both out right away; the next step may move the page.
+        unsafe {
+            Some((
+                from_raw_datum(&kv.key).to_vec(),
+                from_raw_datum(&kv.val).to_vec(),
+            ))
+        }
+    }
+
+    /// Position the cursor at `key`, so that the next call to `next_entry`
+    /// returns that key's entry and the walk carries on from there.
+    ///
+    /// MDBM is unordered, so this means "at this exact key", not "at the
+    /// nearest key". Returns whether the key was found; if it wasn't, the
+    /// cursor is left where it was.
+    pub fn seek(&mut self, key: &[u8]) -> Result<bool, io::Error> {
+        let mut iter = new_iter();
+        let mut raw_key = to_raw_datum(&Datum::new(key));
+        let mut raw_val = mdbm_sys::datum {
+            dptr: ptr::null_mut(),
+            dsize: 0,
+        };
+        let rc =
+            unsafe { mdbm_sys::mdbm_fetch_r(self.db.db, &mut raw_key, &mut raw_val, &mut iter) };
+        if rc == -1 {
+            let err = last_error("fetch");
+            return match MdbmError::from_io(&err).map(|e| e.errno()) {
+                Some(libc::ENOENT) => Ok(false),
+                _ => Err(err),
+            };
+        }
+
+        let value = unsafe { from_raw_datum(&raw_val).to_vec() };
+        self.iter = iter;
+        self.state = IterState::Running;
+        self.pending = Some((key.to_vec(), value));
+        Ok(true)
+    }
+}
+
+/// A held whole-database lock, released on drop. Created by
+/// `MDBM::try_lock_db` and `MDBM::lock_prefix`.
+pub struct DbLock<'a> {
+    db: &'a MDBM,
+}
+
+impl<'a> Drop for DbLock<'a> {
+    fn drop(&mut self) {
+        unsafe {
+            let rc = mdbm_sys::mdbm_unlock(self.db.db);
+            trace_op!(op = "unlock_db", rc = rc);
+
+            assert_eq!(rc, 1);
+        }
+    }
+}
+
+/// Locks on several keys, created by `MDBM::lock_many`.
+pub struct MultiLock<'a> {
+    _locks: Vec<Lock<'a>>,
+}
+
+pub struct Lock<'a> {
+    db: &'a MDBM,
+    key: Datum<'a>,
+    // Only a panic under a write lock can leave the value half-written.
+    write: bool,
+}
+
+impl<'a> Lock<'a> {
+    /// Fetch a key.
+    pub fn get(&'a self) -> Option<&'a [u8]> {
+        unsafe {
+            let value = mdbm_sys::mdbm_fetch(self.db.db, to_raw_datum(&self.key));
+            trace_op!(
+                op = "fetch",
+                key_len = self.key.bytes.len() as u64,
+                found = !value.dptr.is_null()
+            );
+
+            if value.dptr.is_null() {
+                None
+            } else {
+                // Cast pointer from signed char (c) to unsigned char (rust)
+                let u8_ptr: *const u8 = mem::transmute::<*mut i8, *const u8>(value.dptr);
+                Some(slice::from_raw_parts(u8_ptr, value.dsize as usize))
+            }
+        }
+    }
+
+    /// Fetch a key, borrowing the value for as long as the lock is held.
+    ///
+    /// The returned `LockedValue` borrows the lock, so the compiler rejects
+    /// any attempt to keep the bytes around after the lock is released.
+    pub fn value<'l>(&'l self) -> Option<LockedValue<'l>> {
+        self.get().map(|bytes| LockedValue { bytes })
+    }
+
+    /// Iterate lazily over every value stored under the key with
+    /// `StoreMode::InsertDuplicate`, borrowing each while the lock is held.
+    pub fn iter_dups<'l>(&'l self) -> DupIter<'l> {
+        DupIter {
+            db: self.db,
+            key: self.key.clone(),
+            iter: new_iter(),
+            done: false,
+        }
+    }
+
+    /// Fetch a key and apply `f` to its value, or return `None` if the key
+    /// is absent.
+    pub fn map<R, F: FnOnce(&[u8]) -> R>(&self, f: F) -> Option<R> {
+        self.get().map(f)
+    }
+
+    /// Release the lock now, reporting a failed unlock instead of panicking
+    /// as dropping does.
+    pub fn unlock(self) -> Result<(), io::Error> {
+        let rc = unsafe { mdbm_sys::mdbm_unlock_smart(self.db.db, &to_raw_datum(&self.key), 0) };
+        trace_op!(
+            op = "unlock",
+            key_len = self.key.bytes.len() as u64,
+            rc = rc
+        );
+        let result = if rc == 1 {
+            Ok(())
+        } else {
+            Err(self.db.last_lock_error("unlock"))
+        };
+        // Released or not, Drop mustn't unlock again.
+        mem::forget(self);
+        result
+    }
+
+    /// Fetch a key as a plain-old-data value.
+    ///
+    /// Returns `None` if the key is missing or the value is not exactly
+    /// `size_of::<T>()` bytes. When the value happens to be suitably aligned
+    /// for `T` (for example, the database uses `Alignment::Bits64` and `T`
+    /// needs 8 bytes) it is borrowed straight from the mapping; otherwise it
+    /// is copied out, since referencing a misaligned `T` would be undefined
+    /// behavior.
+    #[cfg(feature = "bytemuck")]
+    pub fn get_pod<T: bytemuck::Pod>(&'a self) -> Option<Cow<'a, T>> {
+        let bytes = self.get()?;
+        if bytes.len() != mem::size_of::<T>() {
+            return None;
+        }
+        match bytemuck::try_from_bytes(bytes) {
+            Ok(value) => Some(Cow::Borrowed(value)),
+            Err(_) => Some(Cow::Owned(bytemuck::pod_read_unaligned(bytes))),
+        }
+    }
+}
+
+/// A value borrowed from the database while its key is locked.
+///
+/// It dereferences to `[u8]` and can be used freely inside the lock's scope:
+///
+/// ```no_run
+/// # use rust_mdbm::MDBM;
+/// let db = MDBM::new("example.db", Default::default(), 0o644, 0, 0).unwrap();
+/// db.set(&"hello", &"world", 0).unwrap();
+///
+/// let key = "hello";
+/// let lock = db.lock(&key, 0).unwrap();
+/// let value = lock.value().unwrap();
+/// assert_eq!(&*value, b"world");
+/// ```
+///
+/// The bytes can't escape the lock:
+///
+/// ```compile_fail,E0597
+/// # use rust_mdbm::MDBM;
+/// let db = MDBM::new("example.db", Default::default(), 0o644, 0, 0).unwrap();
+/// let key = "hello";
+/// let value = {
+///     let lock = db.lock(&key, 0).unwrap();
+///     lock.value().unwrap()
+/// };
+/// ```
+///
+/// Nor can they escape the database:
+///
+/// ```compile_fail,E0597
+/// # use rust_mdbm::MDBM;
+/// let value = {
+///     let db = MDBM::new("example.db", Default::default(), 0o644, 0, 0).unwrap();
+///     let key = "hello";
+///     let lock = db.lock(&key, 0).unwrap();
+///     lock.value().unwrap()
+/// };
+/// ```
+///
+/// And a lock can't outlive the key it locks:
+///
+/// ```compile_fail,E0597
+/// # use rust_mdbm::MDBM;
+/// let db = MDBM::new("example.db", Default::default(), 0o644, 0, 0).unwrap();
+/// let lock = {
+///     let key = vec![1u8];
+///     db.lock(&key[..], 0).unwrap()
+/// };
+/// ```
+pub struct LockedValue<'l> {
+    bytes: &'l [u8],
+}
+
+impl<'l> Deref for LockedValue<'l> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        self.bytes
+    }
+}
+
+impl<'l> AsRef<[u8]> for LockedValue<'l> {
+    fn as_ref(&self) -> &[u8] {
+        self.bytes
+    }
+}
+
+/// The values under a duplicated key, created by `Lock::iter_dups`.
+pub struct DupIter<'l> {
+    db: &'l MDBM,
+    key: Datum<'l>,
+    iter: mdbm_sys::MDBM_ITER,
+    done: bool,
+}
+
+impl<'l> Iterator for DupIter<'l> {
+    type Item = &'l [u8];
+
+    fn next(&mut self) -> Option<&'l [u8]> {
+        if self.done {
+            return None;
+        }
+        let mut raw_key = to_raw_datum(&self.key);
+        let mut raw_val = mdbm_sys::datum {
+            dptr: ptr::null_mut(),
+            dsize: 0,
+        };
+        let rc = unsafe {
+            mdbm_sys::mdbm_fetch_dup_r(self.db.db, &mut raw_key, &mut raw_val, &mut self.iter)
+        };
+        if rc == -1 {
+            self.done = true;
+            return None;
+        }
+        // The borrowed lock keeps the key locked, so the value stays put.
+        Some(unsafe { from_raw_datum(&raw_val) })
+    }
+}
+
+/// A value borrowed from the database together with its key's lock, from
+/// `MDBM::get_ref`.
+pub struct ValueGuard<'a> {
+    bytes: &'a [u8],
+    _lock: Lock<'a>,
+}
+
+impl<'a> Deref for ValueGuard<'a> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        self.bytes
+    }
+}
+
+impl<'a> AsRef<[u8]> for ValueGuard<'a> {
+    fn as_ref(&self) -> &[u8] {
+        self.bytes
+    }
+}
+
+/// Space reserved for a value by `MDBM::reserve_value`.
+///
+/// Writes go directly into the database. The value is complete once the
+/// writer drops and releases its lock.
+pub struct ValueWriter<'a> {
+    bytes: &'a mut [u8],
+    _lock: Lock<'a>,
+}
+
+impl<'a> Deref for ValueWriter<'a> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        self.bytes
+    }
+}
+
+impl<'a> DerefMut for ValueWriter<'a> {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        self.bytes
+    }
+}
+
+impl<'a> Lock<'a> {
+    fn poison_id(&self) -> Option<PoisonId> {
+        self.db
+            .file_id()
+            .ok()
+            .map(|id| (id, self.key.bytes.to_vec()))
+    }
+
+    /// Hand back the lock, or wrap it in `LockError::Poisoned` if its key is
+    /// poisoned.
+    fn check_poison(self) -> Result<Lock<'a>, LockError<'a>> {
+        if self.is_poisoned() {
+            return Err(LockError::Poisoned(PoisonedLock { lock: self }));
+        }
+        Ok(self)
+    }
+
+    fn is_poisoned(&self) -> bool {
+        if POISONED_COUNT.load(Ordering::SeqCst) == 0 {
+            return false;
+        }
+        let id = match self.poison_id() {
+            Some(id) => id,
+            None => return false,
+        };
+        let poisoned = POISONED.lock().unwrap_or_else(|e| e.into_inner());
+        poisoned.as_ref().is_some_and(|keys| keys.contains(&id))
+    }
+}
+
+impl<'a> Drop for Lock<'a> {
+    fn drop(&mut self) {
+        if self.write && thread::panicking() {
+            if let Some(id) = self.poison_id() {
+                let mut poisoned = POISONED.lock().unwrap_or_else(|e| e.into_inner());
+                if poisoned.get_or_insert_with(HashSet::new).insert(id) {
+                    POISONED_COUNT.fetch_add(1, Ordering::SeqCst);
+                }
+            }
+        }
+        unsafe {
+            let rc = mdbm_sys::mdbm_unlock_smart(self.db.db, &to_raw_datum(&self.key), 0);
+            trace_op!(
+                op = "unlock",
+                key_len = self.key.bytes.len() as u64,
+                rc = rc
+            );
+
+            assert_eq!(rc, 1);
+        }
+    }
+}
+
+/// Keys whose write `Lock` was dropped while its thread panicked, by the
+/// database file's device and inode, and the key.
+///
+/// Entries are only removed by `clear_poison`, so they outlive the file: a
+/// new file that reuses a deleted file's inode on the same device starts out
+/// with that file's poisoned keys.
+static POISONED: Mutex<Option<HashSet<PoisonId>>> = Mutex::new(None);
+
+/// A key in a particular database file, as `((device, inode), key)`.
+type PoisonId = ((u64, u64), Vec<u8>);
+
+/// The number of keys in `POISONED`, so `lock` can skip looking them up in
+/// the usual case where nothing has panicked.
+static POISONED_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// An error from `MDBM::lock`.
+#[derive(Debug)]
+pub enum LockError<'a> {
+    /// The key couldn't be locked.
+    Io(io::Error),
+    /// The key was locked, but a thread panicked while last holding it, so
+    /// its value may be inconsistent.
+    Poisoned(PoisonedLock<'a>),
+}
+
+impl<'a> From<io::Error> for LockError<'a> {
+    fn from(err: io::Error) -> LockError<'a> {
+        LockError::Io(err)
+    }
+}
+
+/// Unlocks a poisoned key and reports it as an `io::ErrorKind::Other`, for
+/// callers that don't handle poisoning.
+impl<'a> From<LockError<'a>> for io::Error {
+    fn from(err: LockError<'a>) -> io::Error {
+        match err {
+            LockError::Io(err) => err,
+            LockError::Poisoned(_) => io::Error::other("lock poisoned by a panic"),
+        }
+    }
+}
+
+impl<'a> fmt::Display for LockError<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            LockError::Io(ref err) => err.fmt(f),
+            LockError::Poisoned(_) => write!(f, "lock poisoned by a panic"),
+        }
+    }
+}
+
+impl<'a> error::Error for LockError<'a> {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            LockError::Io(ref err) => Some(err),
+            LockError::Poisoned(_) => None,
+        }
+    }
+}
+
+/// A lock on a poisoned key, from `LockError::Poisoned`.
+pub struct PoisonedLock<'a> {
+    lock: Lock<'a>,
+}
+
+impl<'a> PoisonedLock<'a> {
+    /// Take the lock anyway.
+    pub fn into_inner(self) -> Lock<'a> {
+        self.lock
+    }
+
+    /// Borrow the lock, to look at the value.
+    pub fn get_ref(&self) -> &Lock<'a> {
+        &self.lock
+    }
+}
+
+impl<'a> fmt::Debug for PoisonedLock<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("PoisonedLock").finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::MDBM;
+    use std::ffi::CString;
+    use std::fs::remove_file;
+    use std::io;
+    use std::path::Path;
+    use std::str;
+
+    #[test]
+    fn test_set_get() {
+        super::with_temp_db(Default::default(), |db| {
+            db.set(&"hello", &"world", 0).unwrap();
+
+            // key needs to be an lvalue so the lock can hold a reference to
+            // it.
+            let key = "hello";
+
+            // Lock the key. RIAA will unlock it when we exit this scope.
+            let value = db.lock(&key, 0).unwrap();
+
+            // Convert the value into a string. The lock is still live at this
+            // point.
+            let value = str::from_utf8(value.get().unwrap()).unwrap();
+            assert_eq!(value, "world");
+            println!("hello: {}", value);
+        });
+    }
+
+    #[test]
+    fn test_read_only() {
+        let path = Path::new("test_rw.db");
+        let mut opts: super::Options = Default::default();
+        opts.reads = super::ReadState::WriteOnly;
+        opts.hash = Some(super::HashFunction::JENKINS);
+
+        let db = MDBM::new(&path, opts, 0o644, 0, 0).unwrap();
+
+        db.set(&"hello", &"world", 0).unwrap();
+
+        //// Strangely enough, this doesn't fail
+        // let err = db.lock(&"hello", 0);
+
+        // match err {
+        //     Ok(value) => assert!(
+        //         false,
+        //         "WriteOnly should error on read, instead got {}",
+        //         value
+        //             .get()
+        //             .and_then(|v| Some(str::from_utf8(v).unwrap_or("utf8 failure")))
+        //             .unwrap_or("none"),
+        //     ),
+        //     Err(_) => assert!(true),
+        // }
+
+        opts.reads = super::ReadState::ReadOnly;
+        let db = MDBM::new(&path, opts, 0o644, 0, 0).unwrap();
+        let err = db.set(&"another", &"world", 0);
+        match err {
+            Ok(_) => assert!(false, "ReadOnly should error on Write"),
+            Err(_) => assert!(true),
+        }
+
+        // key needs to be an lvalue so the lock can hold a reference to
+        // it.
+        let key = "hello";
+
+        // Lock the key. RIAA will unlock it when we exit this scope.
+        let value = db.lock(&key, 0).unwrap();
+
+        // Convert the value into a string. The lock is still live at this
+        // point.
+        let value = str::from_utf8(value.get().unwrap()).unwrap();
+        assert_eq!(value, "world");
+        println!("hello: {}", value);
+
+        let _ = remove_file(path);
+    }
+
+    #[test]
+    fn test_iter_with_values() {
+        let path = Path::new("test_iter_with_values.db");
+        let db = MDBM::new(path, Default::default(), 0o644, 0, 0).unwrap();
+
+        db.set(&"a", &"1", 0).unwrap();
+        db.set(&"b", &"22", 0).unwrap();
+        db.set(&"c", &"333", 0).unwrap();
+
+        let mut entries: Vec<(Vec<u8>, Vec<u8>)> = db.iter_with_values().collect();
+        entries.sort();
+
+        assert_eq!(
+            entries,
+            vec![
+                (b"a".to_vec(), b"1".to_vec()),
+                (b"b".to_vec(), b"22".to_vec()),
+                (b"c".to_vec(), b"333".to_vec()),
+            ]
+        );
+
+        let _ = remove_file(path);
+    }
+
+    #[test]
+    fn test_hash_mismatch() {
+        let path = Path::new("test_hash_mismatch.db");
+        let _ = remove_file(path);
+
+        let mut opts = super::Options {
+            hash: Some(super::HashFunction::FNV),
+            ..Default::default()
+        };
+        {
+            let db = MDBM::new(path, opts, 0o644, 0, 0).unwrap();
+            db.set(&"hello", &"world", 0).unwrap();
+        }
+
+        // Reopening with the same hash is fine.
+        assert!(MDBM::new(path, opts, 0o644, 0, 0).is_ok());
+
+        opts.hash = Some(super::HashFunction::SHA1);
+        match MDBM::new(path, opts, 0o644, 0, 0) {
+            Ok(_) => panic!("expected a hash mismatch error"),
+            Err(e) => assert_eq!(e.kind(), io::ErrorKind::InvalidInput),
+        }
+
+        let _ = remove_file(path);
+    }
+
+    #[test]
+    fn test_cstr_key() {
+        let path = Path::new("test_cstr_key.db");
+        let db = MDBM::new(path, Default::default(), 0o644, 0, 0).unwrap();
+
+        let ckey = CString::new("hello").unwrap();
+        db.set(ckey.as_c_str(), &"world", 0).unwrap();
+
+        let key = "hello";
+        let value = db.lock(&key, 0).unwrap();
+        assert_eq!(value.get(), Some(&b"world"[..]));
+
+        let _ = remove_file(path);
+    }
+
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn test_tracing_store_event() {
+        use std::fmt;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+        use tracing::field::{Field, Visit};
+        use tracing::span::{Attributes, Id, Record};
+        use tracing::{Event, Metadata, Subscriber};
+
+        // Counts events whose `op` field is "store".
+        struct StoreCounter(Arc<AtomicUsize>);
+
+        struct OpVisitor(bool);
+
+        impl Visit for OpVisitor {
+            fn record_str(&mut self, field: &Field, value: &str) {
+                if field.name() == "op" && value == "store" {
+                    self.0 = true;
+                }
+            }
+
+            fn record_debug(&mut self, _: &Field, _: &dyn fmt::Debug) {}
+        }
+
+        impl Subscriber for StoreCounter {
+            fn enabled(&self, _: &Metadata) -> bool {
+                true
+            }
+            fn new_span(&self, _: &Attributes) -> Id {
+                Id::from_u64(1)
+            }
+            fn record(&self, _: &Id, _: &Record) {}
+            fn record_follows_from(&self, _: &Id, _: &Id) {}
+            fn event(&self, event: &Event) {
+                let mut visitor = OpVisitor(false);
+                event.record(&mut visitor);
+                if visitor.0 {
+                    self.0.fetch_add(1, Ordering::SeqCst);
+                }
+            }
+            fn enter(&self, _: &Id) {}
+            fn exit(&self, _: &Id) {}
+        }
+
+        let path = Path::new("test_tracing_store_event.db");
+        let db = MDBM::new(path, Default::default(), 0o644, 0, 0).unwrap();
+
+        let stores = Arc::new(AtomicUsize::new(0));
+        tracing::subscriber::with_default(StoreCounter(stores.clone()), || {
+            db.set(&"hello", &"world", 0).unwrap();
+        });
+        assert_eq!(stores.load(Ordering::SeqCst), 1);
+
+        let _ = remove_file(path);
+    }
+
+    #[test]
+    fn test_reserve() {
+        let path = Path::new("test_reserve.db");
+        let _ = remove_file(path);
+        let db = MDBM::new(path, Default::default(), 0o644, 0, 0).unwrap();
+
+        db.reserve(1000, 32).unwrap();
+
+        let value = [7u8; 32];
+        for i in 0..1000u32 {
+            let key = format!("key{}", i);
+            db.set(key.as_str(), &value[..], 0).unwrap();
+        }
+        assert_eq!(db.iter_with_values().count(), 1000);
+
+        let err = db.reserve(u32::MAX, usize::MAX).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+
+        let _ = remove_file(path);
+    }
+
+    #[test]
+    fn test_error_context() {
+        let path = Path::new("test_error_context.db");
+        {
+            let _ = MDBM::new(path, Default::default(), 0o644, 0, 0).unwrap();
+        }
+
+        let opts = super::Options {
+            reads: super::ReadState::ReadOnly,
+            ..Default::default()
+        };
+        let db = MDBM::new(path, opts, 0o644, 0, 0).unwrap();
+        let err = db.set(&"hello", &"world", 0).unwrap_err();
+
+        assert!(err.to_string().contains("mdbm store failed"), "{}", err);
+        let details = super::MdbmError::from_io(&err).unwrap();
+        assert_eq!(details.operation(), "store");
+        assert!(err
+            .to_string()
+            .contains(&format!("(errno {})", details.errno())));
+
+        let _ = remove_file(path);
+    }
+
+    #[test]
+    fn test_in_memory() {
+        let db = MDBM::in_memory(Default::default()).unwrap();
+        db.set(&"hello", &"world", 0).unwrap();
+
+        let key = "hello";
+        let value = db.lock(&key, 0).unwrap();
+        assert_eq!(value.get(), Some(&b"world"[..]));
+    }
+
+    #[test]
+    fn test_open_unlinked_leaves_no_file() {
+        let path = super::temp_db_path();
+        let db = MDBM::open_unlinked(path.clone(), Default::default()).unwrap();
+        assert!(!path.exists());
+
+        db.set(&"hello", &"world", 0).unwrap();
+        drop(db);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_alignment_round_trip() {
+        let path = Path::new("test_alignment_round_trip.db");
+        let _ = remove_file(path);
+        let db = MDBM::new(path, Default::default(), 0o644, 0, 0).unwrap();
+
+        db.set_alignment(super::Alignment::Bits64).unwrap();
+        assert_eq!(db.get_alignment(), Some(super::Alignment::Bits64));
+
+        let _ = remove_file(path);
+    }
+
+    #[cfg(feature = "bytemuck")]
+    #[test]
+    fn test_get_pod_alignment() {
+        for &(name, alignment) in &[
+            ("test_get_pod_aligned.db", super::Alignment::Bits64),
+            ("test_get_pod_unaligned.db", super::Alignment::Bits8),
+        ] {
+            let path = Path::new(name);
+            let _ = remove_file(path);
+            let db = MDBM::new(path, Default::default(), 0o644, 0, 0).unwrap();
+            db.set_alignment(alignment).unwrap();
+
+            // Odd-length keys nudge unaligned values off 8-byte boundaries.
+            let expected = [0x0102_0304_0506_0708u64, u64::MAX, 42];
+            let keys = ["a", "bcd", "efghi"];
+            for (key, value) in keys.iter().zip(expected.iter()) {
+                db.set(*key, &value.to_ne_bytes()[..], 0).unwrap();
+            }
+
+            for (key, value) in keys.iter().zip(expected.iter()) {
+                let lock = db.lock(key, 0).unwrap();
+                assert_eq!(*lock.get_pod::<u64>().unwrap(), *value);
+                assert!(lock.get_pod::<u32>().is_none());
+            }
+
+            let _ = remove_file(path);
+        }
+    }
+
+    #[test]
+    fn test_remove_if() {
+        let path = Path::new("test_remove_if.db");
+        let db = MDBM::new(path, Default::default(), 0o644, 0, 0).unwrap();
+
+        db.set(&"lease", &"owner-a", 0).unwrap();
+
+        // Mismatch leaves the value alone.
+        assert!(!db.remove_if(&"lease", &"owner-b").unwrap());
+        assert_eq!(db.lock(&"lease", 0).unwrap().get(), Some(&b"owner-a"[..]));
+
+        // Match deletes it.
+        assert!(db.remove_if(&"lease", &"owner-a").unwrap());
+        assert_eq!(db.lock(&"lease", 0).unwrap().get(), None);
+
+        // Missing key is a no-op.
+        assert!(!db.remove_if(&"lease", &"owner-a").unwrap());
+
+        let _ = remove_file(path);
+    }
+
+    #[test]
+    fn test_store_if_absent() {
+        let path = Path::new("test_store_if_absent.db");
+        let _ = remove_file(path);
+        let db = MDBM::new(path, Default::default(), 0o644, 0, 0).unwrap();
+
+        assert!(db.store_if_absent(&"lock", &"first").unwrap());
+        assert!(!db.store_if_absent(&"lock", &"second").unwrap());
+        assert_eq!(db.lock(&"lock", 0).unwrap().get(), Some(&b"first"[..]));
+
+        let _ = remove_file(path);
+    }
+
+    #[test]
+    fn test_compare_and_swap() {
+        let path = Path::new("test_compare_and_swap.db");
+        let _ = remove_file(path);
+        let db = MDBM::new(path, Default::default(), 0o644, 0, 0).unwrap();
+
+        // Absent-expected: only succeeds while the key is missing.
+        assert!(db.compare_and_swap(&"k", None::<&str>, &"v1").unwrap());
+        assert!(!db.compare_and_swap(&"k", None::<&str>, &"v2").unwrap());
+
+        // Value match swaps.
+        assert!(db.compare_and_swap(&"k", Some("v1"), &"v2").unwrap());
+        assert_eq!(db.lock(&"k", 0).unwrap().get(), Some(&b"v2"[..]));
+
+        // Value mismatch leaves it alone.
+        assert!(!db.compare_and_swap(&"k", Some("v1"), &"v3").unwrap());
+        assert_eq!(db.lock(&"k", 0).unwrap().get(), Some(&b"v2"[..]));
+
+        let _ = remove_file(path);
+    }
+
+    #[test]
+    fn test_store_error_ignores_lock_errno() {
+        use std::sync::mpsc;
+        use std::thread;
+
+        let path = "test_store_error_ignores_lock_errno.db";
+        let _ = remove_file(path);
+        {
+            let _ = MDBM::new(path, Default::default(), 0o644, 0, 0).unwrap();
+        }
+        let opts = super::Options {
+            reads: super::ReadState::ReadOnly,
+            ..Default::default()
+        };
+        let db = MDBM::new(path, opts, 0o644, 0, 0).unwrap();
+
+        // Fail a lock on `db`, which leaves an errno saved on the handle.
+        let (locked_tx, locked_rx) = mpsc::channel();
+        let (done_tx, done_rx) = mpsc::channel::<()>();
+        let holder = thread::spawn(move || {
+            let db = MDBM::new(path, Default::default(), 0o644, 0, 0).unwrap();
+            assert_eq!(unsafe { mdbm_sys::mdbm_lock(db.db) }, 1);
+            locked_tx.send(()).unwrap();
+            let _ = done_rx.recv();
+            unsafe { mdbm_sys::mdbm_unlock(db.db) };
+        });
+        locked_rx.recv().unwrap();
+        assert!(unsafe { mdbm_sys::mdbm_trylock(db.db) } != 1);
+        done_tx.send(()).unwrap();
+        holder.join().unwrap();
+
+        // A later, unrelated failure must not report that stale errno.
+        let err = db.set(&"hello", &"world", 0).unwrap_err();
+        let errno = super::MdbmError::from_io(&err).unwrap().errno();
+        assert!(errno != 0);
+        assert!(errno != libc::EAGAIN && errno != libc::EBUSY, "{}", err);
+
+        let _ = remove_file(path);
+    }
+
+    #[test]
+    fn test_value_exceeds_page_size() {
+        let path = Path::new("test_value_exceeds_page_size.db");
+        let _ = remove_file(path);
+        let db = MDBM::new(path, Default::default(), 0o644, 4096, 0).unwrap();
+
+        let value = vec![0u8; 8192];
+        let err = db.set(&"big", &value[..], 0).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert!(err.to_string().contains("large_objects"), "{}", err);
+
+        // Just under the page size, the page's own bookkeeping doesn't fit.
+        let value = vec![0u8; 4090];
+        let err = db.set(&"big", &value[..], 0).unwrap_err();
+        assert!(err.to_string().contains("may not fit"), "{}", err);
+
+        let _ = remove_file(path);
+    }
+
+    #[test]
+    fn test_iter_pages() {
+        let path = Path::new("test_iter_pages.db");
+        let _ = remove_file(path);
+        let db = MDBM::new(path, Default::default(), 0o644, 512, 0).unwrap();
+
+        let value = [1u8; 40];
+        for i in 0..200 {
+            db.set(format!("key{}", i).as_str(), &value[..], 0).unwrap();
+        }
+
+        let mut all: Vec<(Vec<u8>, Vec<u8>)> = db.iter_with_values().collect();
+        let mut bucketed = Vec::new();
+        let pages = db.iter_pages();
+        assert_eq!(pages.unplaced(), 0);
+        for (page, entries) in pages {
+            for (key, value) in entries {
+                assert_eq!(db.page_for_key(&key[..]).unwrap(), page);
+                bucketed.push((key, value));
+            }
+        }
+        all.sort();
+        bucketed.sort();
+        assert_eq!(all, bucketed);
+
+        let _ = remove_file(path);
+    }
+
+    #[test]
+    fn test_set_hash() {
+        let path = Path::new("test_set_hash.db");
+        let _ = remove_file(path);
+        let db = MDBM::new(path, Default::default(), 0o644, 0, 0).unwrap();
+
+        db.set_hash(super::HashFunction::MD5).unwrap();
+        match db.get_hash() {
+            Some(super::HashFunction::MD5) => {}
+            other => panic!("expected MD5, got {:?}", other),
+        }
+
+        db.set(&"hello", &"world", 0).unwrap();
+        let err = db.set_hash(super::HashFunction::FNV).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+
+        let _ = remove_file(path);
+    }
+
+    #[test]
+    fn test_locked_value() {
+        let path = Path::new("test_locked_value.db");
+        let db = MDBM::new(path, Default::default(), 0o644, 0, 0).unwrap();
+        db.set(&"hello", &"world", 0).unwrap();
+
+        let key = "hello";
+        let lock = db.lock(&key, 0).unwrap();
+        let value = lock.value().unwrap();
+        assert_eq!(&*value, b"world");
+        assert_eq!(str::from_utf8(&value).unwrap(), "world");
+
+        let _ = remove_file(path);
+    }
+
+    #[test]
+    fn test_retry_transient() {
+        use std::time::Duration;
+
+        // The first attempt fails transiently; the second succeeds.
+        let mut attempts = 0;
+        let result = super::retry_transient(3, Duration::from_millis(1), || {
+            attempts += 1;
+            if attempts == 1 {
+                Err(io::Error::from_raw_os_error(libc::EAGAIN))
+            } else {
+                Ok(attempts)
+            }
+        });
+        assert_eq!(result.unwrap(), 2);
+
+        // Other errors are not retried.
+        let mut attempts = 0;
+        let result: Result<(), io::Error> =
+            super::retry_transient(3, Duration::from_millis(1), || {
+                attempts += 1;
+                Err(io::Error::from_raw_os_error(libc::ENOENT))
+            });
+        assert!(result.is_err());
+        assert_eq!(attempts, 1);
+
+        // Transient errors give up after the retries run out.
+        let mut attempts = 0;
+        let result: Result<(), io::Error> =
+            super::retry_transient(2, Duration::from_millis(1), || {
+                attempts += 1;
+                Err(io::Error::from_raw_os_error(libc::EBUSY))
+            });
+        assert!(result.is_err());
+        assert_eq!(attempts, 3);
+    }
+
+    #[test]
+    fn test_open_with_retry() {
+        use std::time::Duration;
+
+        let path = Path::new("test_open_with_retry.db");
+        let db = MDBM::open_with_retry(
+            path,
+            Default::default(),
+            0o644,
+            0,
+            0,
+            3,
+            Duration::from_millis(1),
+        )
+        .unwrap();
+        db.set(&"hello", &"world", 0).unwrap();
+
+        let _ = remove_file(path);
+    }
+
+    #[test]
+    fn test_value_size_histogram() {
+        let path = Path::new("test_value_size_histogram.db");
+        let _ = remove_file(path);
+        let db = MDBM::new(path, Default::default(), 0o644, 0, 0).unwrap();
+
+        for (i, size) in [1, 8, 10, 16, 100, 1000].iter().enumerate() {
+            let value = vec![0u8; *size];
+            db.set(format!("key{}", i).as_str(), &value[..], 0).unwrap();
+        }
+
+        assert_eq!(db.value_size_histogram(&[8, 16, 128]), vec![2, 2, 1, 1]);
+        assert_eq!(db.value_size_histogram(&[]), vec![6]);
+
+        let _ = remove_file(path);
+    }
+
+    #[test]
+    fn test_cursor_seek() {
+        let path = Path::new("test_cursor_seek.db");
+        let _ = remove_file(path);
+        let db = MDBM::new(path, Default::default(), 0o644, 0, 0).unwrap();
+
+        for i in 0..10 {
+            db.set(
+                format!("key{}", i).as_str(),
+                format!("value{}", i).as_str(),
+                0,
+            )
+            .unwrap();
+        }
+
+        // Pick whatever key comes up mid-walk, then seek a fresh cursor to it.
+        let order: Vec<(Vec<u8>, Vec<u8>)> = db.iter_with_values().collect();
+        let middle = &order[5];
+
+        let mut cursor = db.cursor();
+        assert!(cursor.seek(&middle.0).unwrap());
+        assert_eq!(cursor.next_entry().as_ref(), Some(middle));
+        assert_eq!(cursor.next_entry().as_ref(), order.get(6));
+
+        assert!(!cursor.seek(b"missing").unwrap());
+
+        let _ = remove_file(path);
+    }
+
+    #[test]
+    fn test_extend() {
+        let path = Path::new("test_extend.db");
+        let _ = remove_file(path);
+        let mut db = MDBM::new(path, Default::default(), 0o644, 0, 0).unwrap();
+
+        db.extend(vec![("a", "1"), ("b", "2")]);
+        let keys: Vec<String> = (0..3).map(|i| format!("key{}", i)).collect();
+        let values: Vec<[u8; 1]> = (0..3).map(|i| [i as u8]).collect();
+        db.extend(
+            keys.iter()
+                .map(String::as_str)
+                .zip(values.iter().map(|v| &v[..])),
+        );
+        let minus_one = CString::new("minus one").unwrap();
+        db.extend(vec![("c", minus_one.as_c_str())]);
+
+        assert_eq!(db.lock(&"a", 0).unwrap().get(), Some(&b"1"[..]));
+        assert_eq!(db.lock(&"b", 0).unwrap().get(), Some(&b"2"[..]));
+        for i in 0..3 {
+            let key = format!("key{}", i);
+            assert_eq!(
+                db.lock(key.as_str(), 0).unwrap().get(),
+                Some(&[i as u8][..])
+            );
+        }
+        assert_eq!(db.lock(&"c", 0).unwrap().get(), Some(&b"minus one"[..]));
+
+        let _ = remove_file(path);
+    }
+
+    #[test]
+    fn test_snapshot() {
+        let path = Path::new("test_snapshot.db");
+        let snap_path = Path::new("test_snapshot_copy.db");
+        let _ = remove_file(path);
+        let db = MDBM::new(path, Default::default(), 0o644, 0, 0).unwrap();
+
+        db.set(&"hello", &"world", 0).unwrap();
+        let snap = db.snapshot(snap_path).unwrap();
+
+        db.set(&"hello", &"there", 0).unwrap();
+        db.set(&"new", &"key", 0).unwrap();
+
+        assert_eq!(snap.lock(&"hello", 0).unwrap().get(), Some(&b"world"[..]));
+        assert_eq!(snap.lock(&"new", 0).unwrap().get(), None);
+        assert_eq!(db.lock(&"hello", 0).unwrap().get(), Some(&b"there"[..]));
+
+        let _ = remove_file(path);
+        let _ = remove_file(snap_path);
+    }
+
+    #[test]
+    fn test_ord_i64() {
+        use super::{AsDatum, OrdI64};
+
+        let values = [i64::MIN, -1000, -1, 0, 1, 1000, i64::MAX];
+        let encoded: Vec<OrdI64> = values.iter().map(|&v| OrdI64::new(v)).collect();
+        for pair in encoded.windows(2) {
+            assert!(pair[0].as_datum().bytes < pair[1].as_datum().bytes);
+        }
+        for (&v, e) in values.iter().zip(encoded.iter()) {
+            assert_eq!(e.get(), v);
+            assert_eq!(OrdI64::decode(e.as_datum().bytes), Some(v));
+        }
+        assert_eq!(OrdI64::decode(b"short"), None);
+
+        let path = Path::new("test_ord_i64.db");
+        let db = MDBM::new(path, Default::default(), 0o644, 0, 0).unwrap();
+        db.set(&OrdI64::new(-5), &"minus five", 0).unwrap();
+        let key = OrdI64::from(-5);
+        assert_eq!(db.lock(&key, 0).unwrap().get(), Some(&b"minus five"[..]));
+
+        let _ = remove_file(path);
+    }
+
+    #[test]
+    fn test_close() {
+        let path = Path::new("test_close.db");
+        let db = MDBM::new(path, Default::default(), 0o644, 0, 0).unwrap();
+        db.set(&"hello", &"world", 0).unwrap();
+        db.close().unwrap();
+
+        let db = MDBM::new(path, Default::default(), 0o644, 0, 0).unwrap();
+        assert_eq!(db.lock(&"hello", 0).unwrap().get(), Some(&b"world"[..]));
+
+        let _ = remove_file(path);
+    }
+
+    #[test]
+    fn test_op_counter() {
+        use super::StatCounter;
+
+        let path = Path::new("test_op_counter.db");
+        let db = MDBM::new(path, Default::default(), 0o644, 0, 0).unwrap();
+        db.enable_stat_operations().unwrap();
+
+        let before = db.op_counter(StatCounter::Store);
+        for i in 0..5 {
+            db.set(format!("key{}", i).as_str(), &"value", 0).unwrap();
+        }
+        assert_eq!(db.op_counter(StatCounter::Store), before + 5);
+
+        let _ = remove_file(path);
+    }
+
+    #[test]
+    fn test_with_lock() {
+        let path = Path::new("test_with_lock.db");
+        let db = MDBM::new(path, Default::default(), 0o644, 0, 0).unwrap();
+
+        let result = db
+            .with_lock(|db| {
+                db.set(&"a", &"1", 0)?;
+                db.set(&"b", &"2", 0)
+            })
+            .unwrap();
+        result.unwrap();
+
+        assert_eq!(db.lock(&"a", 0).unwrap().get(), Some(&b"1"[..]));
+        assert_eq!(db.lock(&"b", 0).unwrap().get(), Some(&b"2"[..]));
+
+        // A panic inside releases the lock, so it can be taken again.
+        let panicked = ::std::panic::catch_unwind(::std::panic::AssertUnwindSafe(|| {
+            db.with_lock(|_| panic!("boom")).unwrap();
+        }));
+        assert!(panicked.is_err());
+        db.with_lock(|_| ()).unwrap();
+
+        let _ = remove_file(path);
+    }
+
+    #[test]
+    fn test_stats_callback() {
+        use super::StatType;
+        use std::cell::RefCell;
+        use std::rc::Rc;
+
+        let path = Path::new("test_stats_callback.db");
+        let mut db = MDBM::new(path, Default::default(), 0o644, 0, 0).unwrap();
+        db.enable_stat_operations().unwrap();
+
+        let events = Rc::new(RefCell::new(Vec::new()));
+        let seen = events.clone();
+        db.set_stats_callback(move |stat, value| seen.borrow_mut().push((stat, value)))
+            .unwrap();
+
+        db.set(&"hello", &"world", 0).unwrap();
+        assert_eq!(db.lock(&"hello", 0).unwrap().get(), Some(&b"world"[..]));
+        assert!(events.borrow().iter().any(|&(s, _)| s == StatType::Store));
+        assert!(events.borrow().iter().any(|&(s, _)| s == StatType::Fetch));
+
+        db.clear_stats_callback().unwrap();
+        let count = events.borrow().len();
+        db.set(&"hello", &"again", 0).unwrap();
+        assert_eq!(events.borrow().len(), count);
+
+        let _ = remove_file(path);
+    }
+
+    #[test]
+    fn test_shrink_to_fit() {
+        let path = Path::new("test_shrink_to_fit.db");
+        let _ = remove_file(path);
+        let db = MDBM::new(path, Default::default(), 0o644, 1024, 0).unwrap();
+
+        let value = [0u8; 64];
+        for i in 0..1000 {
+            db.set(format!("key{}", i).as_str(), &value[..], 0).unwrap();
+        }
+        // Freshly split pages are well used.
+        assert!(!db.shrink_to_fit(0.9).unwrap());
+
+        for i in 10..1000 {
+            db.delete(format!("key{}", i).as_str()).unwrap();
+        }
+        assert!(db.shrink_to_fit(0.9).unwrap());
+        assert_eq!(db.iter_with_values().count(), 10);
+
+        let _ = remove_file(path);
+    }
+
+    #[test]
+    fn test_lock_many() {
+        use std::thread;
+
+        let path = "test_lock_many.db";
+        let _ = remove_file(path);
+        {
+            let _ = MDBM::new(path, Default::default(), 0o644, 0, 0).unwrap();
+        }
+
+        let orders: [[&'static [u8]; 3]; 2] = [[b"a", b"b", b"c"], [b"c", b"a", b"b"]];
+        let threads: Vec<_> = orders
+            .iter()
+            .map(|order| {
+                let order = *order;
+                thread::spawn(move || {
+                    let db = MDBM::new(path, Default::default(), 0o644, 0, 0).unwrap();
+                    for i in 0..100 {
+                        let _locks = db.lock_many(&order, 0).unwrap();
+                        for key in order.iter() {
+                            db.set(*key, format!("{}", i).as_str(), 0).unwrap();
+                        }
+                    }
+                })
+            })
+            .collect();
+        for t in threads {
+            t.join().unwrap();
+        }
+
+        let _ = remove_file(path);
+    }
+
+    #[test]
+    fn test_page_size_validation() {
+        let path = Path::new("test_page_size_validation.db");
+        let _ = remove_file(path);
+
+        let err = MDBM::new(path, Default::default(), 0o644, 1000, 0)
+            .err()
+            .expect("a page size of 1000 should be rejected");
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert!(err.to_string().contains("power of two"));
+        assert!(!path.exists());
+
+        assert!(MDBM::new(path, Default::default(), 0o644, 4096, 0).is_ok());
+
+        let _ = remove_file(path);
+    }
+
+    #[test]
+    fn test_dump_to() {
+        use super::DumpEncoding;
+
+        let path = Path::new("test_dump_to.db");
+        let _ = remove_file(path);
+        let db = MDBM::new(path, Default::default(), 0o644, 0, 0).unwrap();
+        db.set(&"a", &"hello", 0).unwrap();
+        db.set(&"bc", &[0xffu8, 0, 7][..], 0).unwrap();
+
+        let dump = |encoding| {
+            let mut out = Vec::new();
+            db.dump_to(&mut out, encoding).unwrap();
+            let mut lines: Vec<String> = String::from_utf8(out)
+                .unwrap()
+                .lines()
+                .map(String::from)
+                .collect();
+            lines.sort();
+            lines
+        };
+
+        assert_eq!(
+            dump(DumpEncoding::Utf8Lossy),
+            vec!["a\thello", "bc\t\u{fffd}\u{0}\u{7}"]
+        );
+        assert_eq!(
+            dump(DumpEncoding::Base64),
+            vec!["YQ==\taGVsbG8=", "YmM=\t/wAH"]
+        );
+        assert_eq!(
+            dump(DumpEncoding::Hex),
+            vec!["61\t68656c6c6f", "6263\tff0007"]
+        );
+
+        let _ = remove_file(path);
+    }
+
+    #[test]
+    fn test_load_from() {
+        use super::{DumpEncoding, StoreMode};
+
+        let path = Path::new("test_load_from.db");
+        let _ = remove_file(path);
+        let db = MDBM::new(path, Default::default(), 0o644, 0, 0).unwrap();
+        db.set(&"a", &"hello", 0).unwrap();
+        db.set(&"bc", &[0xffu8, 0, 7][..], 0).unwrap();
+        db.set(&"", &"empty key", 0).unwrap();
+
+        let mut before: Vec<_> = db.iter_with_values().collect();
+        before.sort();
+
+        let mut dump = Vec::new();
+        db.dump_to(&mut dump, DumpEncoding::Base64).unwrap();
+        for (key, _) in &before {
+            db.delete(&key[..]).unwrap();
+        }
+        assert_eq!(db.iter_with_values().count(), 0);
+
+        let loaded = db
+            .load_from(&mut &dump[..], DumpEncoding::Base64, StoreMode::Insert)
+            .unwrap();
+        assert_eq!(loaded, 3);
+        let mut after: Vec<_> = db.iter_with_values().collect();
+        after.sort();
+        assert_eq!(before, after);
+
+        let bad = "61\t62\nnot-a-pair\n";
+        let err = db
+            .load_from(&mut bad.as_bytes(), DumpEncoding::Hex, StoreMode::Replace)
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(err.to_string().starts_with("line 2:"));
+
+        let _ = remove_file(path);
+    }
+
+    #[test]
+    fn test_datum_ord() {
+        use super::Datum;
+
+        let mut keys = vec![
+            Datum::new(b"b"),
+            Datum::new(b"ab"),
+            Datum::new(b""),
+            Datum::new(b"a"),
+            Datum::new(&[0xff]),
+        ];
+        keys.sort();
+        assert_eq!(
+            keys,
+            vec![
+                Datum::new(b""),
+                Datum::new(b"a"),
+                Datum::new(b"ab"),
+                Datum::new(b"b"),
+                Datum::new(&[0xff]),
+            ]
+        );
+        assert!(Datum::new(b"a") == Datum::new(&b"abc"[..1]));
+    }
+
+    #[test]
+    fn test_reserve_value() {
+        let path = Path::new("test_reserve_value.db");
+        let _ = remove_file(path);
+        let db = MDBM::new(path, Default::default(), 0o644, 0, 0).unwrap();
+        db.set(&"key", &"old", 0).unwrap();
+
+        {
+            let mut writer = db.reserve_value(&"key", 64).unwrap();
+            assert_eq!(writer.len(), 64);
+            assert!(writer.iter().all(|&b| b == 0));
+            for (i, b) in writer.iter_mut().enumerate() {
+                *b = i as u8;
+            }
+        }
+
+        let key = "key";
+        let lock = db.lock(&key, 0).unwrap();
+        let expected: Vec<u8> = (0..64).collect();
+        assert_eq!(lock.get().unwrap(), &expected[..]);
+        drop(lock);
+
+        let too_big = libc::c_int::MAX as usize + 1;
+        let err = db.reserve_value(&"big", too_big).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+
+        let _ = remove_file(path);
+    }
+
+    #[test]
+    fn test_delete_prefix() {
+        let path = Path::new("test_delete_prefix.db");
+        let _ = remove_file(path);
+        let db = MDBM::new(path, Default::default(), 0o644, 0, 0).unwrap();
+        for i in 0..50 {
+            db.set(format!("alpha/{}", i).as_str(), &"a", 0).unwrap();
+            db.set(format!("beta/{}", i).as_str(), &"b", 0).unwrap();
+        }
+
+        assert_eq!(db.delete_prefix(b"alpha/").unwrap(), 50);
+        assert_eq!(db.delete_prefix(b"alpha/").unwrap(), 0);
+
+        let keys: Vec<_> = db.iter_with_values().map(|(k, _)| k).collect();
+        assert_eq!(keys.len(), 50);
+        assert!(keys.iter().all(|k| k.starts_with(b"beta/")));
+
+        let _ = remove_file(path);
+    }
+
+    #[test]
+    fn test_put() {
+        use super::StoreMode;
+
+        let path = Path::new("test_put.db");
+        let _ = remove_file(path);
+        let mut db = MDBM::new(path, Default::default(), 0o644, 0, 0).unwrap();
+
+        db.put(&"key", &"first").unwrap();
+        db.put(&"key", &"second").unwrap();
+
+        db.set_default_store_mode(StoreMode::Insert);
+        let err = db.put(&"key", &"third").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+        db.put(&"other", &"value").unwrap();
+
+        let key = "key";
+        let lock = db.lock(&key, 0).unwrap();
+        assert_eq!(lock.get().unwrap(), b"second");
+
+        let _ = remove_file(path);
+    }
+
+    #[test]
+    fn test_path() {
+        let path = Path::new("test_path.db");
+        let _ = remove_file(path);
+        let db = MDBM::new(path, Default::default(), 0o644, 0, 0).unwrap();
+        assert_eq!(db.path(), path);
+
+        let _ = remove_file(path);
+    }
+
+    #[test]
+    fn test_iter_since() {
+        let path = Path::new("test_iter_since.db");
+        let _ = remove_file(path);
+        let db = MDBM::new(path, Default::default(), 0o644, 0, 0).unwrap();
+        for (seq, key) in ["a", "b", "c", "d"].iter().enumerate() {
+            db.set(*key, format!("{}:{}", seq + 1, key).as_str(), 0)
+                .unwrap();
+        }
+        db.set(&"untagged", &"no sequence", 0).unwrap();
+
+        let seq_of = |value: &[u8]| {
+            let text = str::from_utf8(value).ok()?;
+            text.split(':').next()?.parse().ok()
+        };
+        let mut keys: Vec<_> = db.iter_since(2, seq_of).map(|(k, _)| k).collect();
+        keys.sort();
+        assert_eq!(keys, vec![b"c".to_vec(), b"d".to_vec()]);
+        assert_eq!(db.iter_since(4, seq_of).count(), 0);
+
+        let _ = remove_file(path);
+    }
+
+    #[test]
+    fn test_merge() {
+        use super::ConflictPolicy;
+
+        let fill = |name: &str, entries: &[(&str, &str)]| {
+            let path = Path::new(name);
+            let _ = remove_file(path);
+            let db = MDBM::new(path, Default::default(), 0o644, 0, 0).unwrap();
+            for (k, v) in entries {
+                db.set(*k, *v, 0).unwrap();
+            }
+            db
+        };
+        let value = |db: &MDBM, key: &str| {
+            let lock = db.lock(key, 0).unwrap();
+            lock.get().map(|v| v.to_vec())
+        };
+        let other = fill("test_merge_other.db", &[("shared", "theirs"), ("new", "n")]);
+
+        let db = fill("test_merge_keep.db", &[("shared", "ours")]);
+        assert_eq!(db.merge(&other, ConflictPolicy::KeepExisting).unwrap(), 1);
+        assert_eq!(value(&db, "shared").unwrap(), b"ours");
+        assert_eq!(value(&db, "new").unwrap(), b"n");
+
+        let db = fill("test_merge_overwrite.db", &[("shared", "ours")]);
+        assert_eq!(db.merge(&other, ConflictPolicy::Overwrite).unwrap(), 2);
+        assert_eq!(value(&db, "shared").unwrap(), b"theirs");
+
+        let db = fill("test_merge_error.db", &[("shared", "ours")]);
+        let err = db.merge(&other, ConflictPolicy::Error).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+        assert_eq!(value(&db, "shared").unwrap(), b"ours");
+
+        for name in &[
+            "test_merge_other.db",
+            "test_merge_keep.db",
+            "test_merge_overwrite.db",
+            "test_merge_error.db",
+        ] {
+            let _ = remove_file(name);
+        }
+    }
+
+    #[test]
+    fn test_diff() {
+        let open = |name: &str, entries: &[(&str, &str)]| {
+            let path = Path::new(name);
+            let _ = remove_file(path);
+            let db = MDBM::new(path, Default::default(), 0o644, 0, 0).unwrap();
+            for (k, v) in entries {
+                db.set(*k, *v, 0).unwrap();
+            }
+            db
+        };
+        let original = open(
+            "test_diff_original.db",
+            &[("same", "1"), ("removed", "2"), ("changed", "3")],
+        );
+        let rebuilt = open(
+            "test_diff_rebuilt.db",
+            &[("same", "1"), ("added", "4"), ("changed", "three")],
+        );
+
+        let diff = original.diff(&rebuilt).unwrap();
+        assert_eq!(diff.only_in_self, vec![b"removed".to_vec()]);
+        assert_eq!(diff.only_in_other, vec![b"added".to_vec()]);
+        assert_eq!(diff.value_mismatch, vec![b"changed".to_vec()]);
+        assert_eq!(original.diff(&original).unwrap(), Default::default());
+
+        let _ = remove_file("test_diff_original.db");
+        let _ = remove_file("test_diff_rebuilt.db");
+    }
+
+    #[cfg(feature = "tokio")]
+    #[test]
+    fn test_async_mdbm() {
+        use super::AsyncMdbm;
+        use std::rc::Rc;
+
+        let path = Path::new("test_async_mdbm.db");
+        let _ = remove_file(path);
+        let mut base = MDBM::new(path, Default::default(), 0o644, 0, 0).unwrap();
+        // A callback that mustn't leave this thread goes with the handle.
+        let local = Rc::new(());
+        let captured = Rc::clone(&local);
+        base.set_stats_callback(move |_, _| drop(Rc::clone(&captured)))
+            .unwrap();
+        let db = AsyncMdbm::new(base).unwrap();
+        assert_eq!(Rc::strong_count(&local), 1);
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+
+        rt.block_on(db.store(b"key".to_vec(), b"value".to_vec(), 0))
+            .unwrap();
+        let value = rt.block_on(db.fetch(b"key".to_vec())).unwrap();
+        assert_eq!(value.as_deref(), Some(&b"value"[..]));
+
+        rt.block_on(db.delete(b"key".to_vec())).unwrap();
+        assert_eq!(rt.block_on(db.fetch(b"key".to_vec())).unwrap(), None);
+
+        let _ = remove_file(path);
+    }
+
+    #[test]
+    fn test_dup_handle() {
+        let path = Path::new("test_dup_handle.db");
+        let _ = remove_file(path);
+        let db = MDBM::new(path, Default::default(), 0o644, 0, 0).unwrap();
+        let dup = db.dup_handle().unwrap();
+
+        db.set(&"key", &"value", 0).unwrap();
+        assert_eq!(dup.fetch(&"key").unwrap().as_deref(), Some(&b"value"[..]));
+        assert_eq!(dup.path(), path);
+
+        let _ = remove_file(path);
+    }
+
+    #[test]
+    fn test_lock_timeout() {
+        use std::sync::mpsc;
+        use std::thread;
+        use std::time::{Duration, Instant};
+
+        let path = "test_lock_timeout.db";
+        let _ = remove_file(path);
+        let db = MDBM::new(path, Default::default(), 0o644, 0, 0).unwrap();
+        db.set(&"key", &"value", 0).unwrap();
+
+        let (locked_tx, locked_rx) = mpsc::channel();
+        let (done_tx, done_rx) = mpsc::channel::<()>();
+        let holder = thread::spawn(move || {
+            let db = MDBM::new(path, Default::default(), 0o644, 0, 0).unwrap();
+            let key = "key";
+            let _lock = db.lock(&key, super::WRITE_LOCK).unwrap();
+            locked_tx.send(()).unwrap();
+            let _ = done_rx.recv();
+        });
+        locked_rx.recv().unwrap();
+
+        let key = "key";
+        let timeout = Duration::from_millis(100);
+        let start = Instant::now();
+        assert!(db.lock_timeout(&key, timeout, 0).unwrap().is_none());
+        assert!(start.elapsed() >= timeout);
+        assert!(db.try_lock(&key, 0).unwrap().is_none());
+
+        done_tx.send(()).unwrap();
+        holder.join().unwrap();
+        assert!(db.lock_timeout(&key, timeout, 0).unwrap().is_some());
+
+        let _ = remove_file(path);
+    }
+
+    #[test]
+    fn test_entry_or_default() {
+        let path = Path::new("test_entry_or_default.db");
+        let _ = remove_file(path);
+        let db = MDBM::new(path, Default::default(), 0o644, 0, 0).unwrap();
+
+        let value = db.entry_or_default(&"counter", || "0").unwrap();
+        assert_eq!(value, b"0");
+        assert_eq!(db.fetch(&"counter").unwrap().as_deref(), Some(&b"0"[..]));
+
+        let value = db
+            .entry_or_default(&"counter", || -> &str { panic!("default called again") })
+            .unwrap();
+        assert_eq!(value, b"0");
+
+        let _ = remove_file(path);
+    }
+
+    #[test]
+    fn test_as_raw() {
+        let path = Path::new("test_as_raw.db");
+        let _ = remove_file(path);
+        let opts = super::Options {
+            hash: Some(super::HashFunction::FNV),
+            ..Default::default()
+        };
+        let db = MDBM::new(path, opts, 0o644, 0, 0).unwrap();
+
+        let raw = unsafe { mdbm_sys::mdbm_get_hash(db.as_raw()) };
+        assert_eq!(Some(raw as u32), db.get_hash().map(|h| h.hash_constant()));
+
+        let _ = remove_file(path);
+    }
+
+    #[test]
+    fn test_builder() {
+        use super::HashFunction;
+
+        let path = Path::new("test_builder.db");
+        let _ = remove_file(path);
+
+        assert!(MDBM::open(path).create(false).finish().is_err());
+
+        {
+            let db = MDBM::open(path)
+                .read_write()
+                .create(true)
+                .hash(HashFunction::FNV)
+                .mode(0o600)
+                .page_size(4096)
+                .finish()
+                .unwrap();
+            db.set(&"hello", &"world", 0).unwrap();
+            assert_eq!(db.path(), path);
+            assert_eq!(
+                db.get_hash().map(|h| h.hash_constant()),
+                Some(HashFunction::FNV.hash_constant())
+            );
+        }
+
+        let opts = super::Options {
+            reads: super::ReadState::ReadOnly,
+            create: false,
+            ..Default::default()
+        };
+        let via_new = MDBM::new(path, opts, 0o644, 0, 0).unwrap();
+        let via_builder = MDBM::open(path).read_only().create(false).finish().unwrap();
+        assert_eq!(
+            via_new.fetch(&"hello").unwrap(),
+            via_builder.fetch(&"hello").unwrap()
+        );
+        assert!(via_builder.set(&"other", &"value", 0).is_err());
+
+        let _ = remove_file(path);
+    }
+
+    #[test]
+    fn test_fill_ratio() {
+        let path = Path::new("test_fill_ratio.db");
+        let _ = remove_file(path);
+        let db = MDBM::new(path, Default::default(), 0o644, 0, 0).unwrap();
+
+        let empty = db.fill_ratio();
+        assert!(empty < 0.1, "fill ratio of an empty db was {}", empty);
+        let depth = db.directory_depth();
+
+        let value = [7u8; 200];
+        for i in 0..2000 {
+            db.set(format!("key{}", i).as_str(), &value[..], 0).unwrap();
+        }
+        let full = db.fill_ratio();
+        assert!(
+            full > empty && full <= 1.0,
+            "fill ratio went from {} to {}",
+            empty,
+            full
+        );
+        assert!(db.directory_depth() > depth);
+
+        let _ = remove_file(path);
+    }
+
+    #[test]
+    fn test_array_keys() {
+        let path = Path::new("test_array_keys.db");
+        let _ = remove_file(path);
+        let db = MDBM::new(path, Default::default(), 0o644, 0, 0).unwrap();
+
+        let wide = [7u8; 64];
+        db.set(&wide, &[1u8, 2, 3], 0).unwrap();
+        assert_eq!(
+            db.fetch(&wide[..]).unwrap().as_deref(),
+            Some(&[1u8, 2, 3][..])
+        );
+
+        let _ = remove_file(path);
+    }
+
+    #[test]
+    fn test_copy_value() {
+        let path = Path::new("test_copy_value.db");
+        let _ = remove_file(path);
+        let db = MDBM::new(path, Default::default(), 0o644, 0, 0).unwrap();
+        db.set(&"from", &"value", 0).unwrap();
+        db.set(&"to", &"old", 0).unwrap();
+
+        assert!(db.copy_value(&"from", &"to").unwrap());
+        assert_eq!(db.fetch(&"from").unwrap().as_deref(), Some(&b"value"[..]));
+        assert_eq!(db.fetch(&"to").unwrap().as_deref(), Some(&b"value"[..]));
+
+        assert!(!db.copy_value(&"missing", &"to").unwrap());
+        assert_eq!(db.fetch(&"to").unwrap().as_deref(), Some(&b"value"[..]));
+        assert!(db.copy_value(&"from", &"from").unwrap());
+
+        let _ = remove_file(path);
+    }
+
+    #[test]
+    fn test_rename_key() {
+        let path = Path::new("test_rename_key.db");
+        let _ = remove_file(path);
+        let db = MDBM::new(path, Default::default(), 0o644, 0, 0).unwrap();
+        db.set(&"old", &"value", 0).unwrap();
+
+        assert!(db.rename_key(&"old", &"new").unwrap());
+        assert_eq!(db.fetch(&"old").unwrap(), None);
+        assert_eq!(db.fetch(&"new").unwrap().as_deref(), Some(&b"value"[..]));
+
+        assert!(!db.rename_key(&"old", &"new").unwrap());
+        assert!(db.rename_key(&"new", &"new").unwrap());
+        assert_eq!(db.fetch(&"new").unwrap().as_deref(), Some(&b"value"[..]));
+
+        let _ = remove_file(path);
+    }
+
+    #[test]
+    fn test_cache_mode() {
+        use super::{CacheMode, StatType};
+        use std::cell::Cell;
+        use std::ptr;
+        use std::rc::Rc;
+
+        let path = Path::new("test_cache_mode.db");
+        let _ = remove_file(path);
+        let mut db = MDBM::new(path, Default::default(), 0o644, 4096, 0).unwrap();
+        assert_eq!(db.get_cache_mode(), None);
+        db.set_cache_mode(CacheMode::Lru).unwrap();
+        assert_eq!(db.get_cache_mode(), Some(CacheMode::Lru));
+        let rc = unsafe { mdbm_sys::mdbm_limit_size_v3(db.as_raw(), 4, None, ptr::null_mut()) };
+        assert_eq!(rc, 0);
+
+        let evictions = Rc::new(Cell::new(0));
+        let counter = evictions.clone();
+        db.set_stats_callback(move |stat, _| {
+            if stat == StatType::CacheEvict {
+                counter.set(counter.get() + 1);
+            }
+        })
+        .unwrap();
+
+        let value = [1u8; 256];
+        for i in 0..200 {
+            db.set(format!("key{}", i).as_str(), &value[..], 0).unwrap();
+        }
+        assert!(evictions.get() > 0);
+
+        let _ = remove_file(path);
+    }
+
+    #[test]
+    fn test_try_open() {
+        let path = Path::new("test_try_open.db");
+        let _ = remove_file(path);
+        let opts = super::Options {
+            create: false,
+            ..Default::default()
+        };
+
+        assert!(MDBM::try_open(path, opts, 0o644, 0, 0).unwrap().is_none());
+        assert!(!path.exists());
+
+        MDBM::new(path, Default::default(), 0o644, 0, 0).unwrap();
+        assert!(MDBM::try_open(path, opts, 0o644, 0, 0).unwrap().is_some());
+        assert!(MDBM::try_open(path, opts, 0o644, 1000, 0).is_err());
+
+        let _ = remove_file(path);
+    }
+
+    #[test]
+    fn test_lock_map() {
+        let path = Path::new("test_lock_map.db");
+        let _ = remove_file(path);
+        let db = MDBM::new(path, Default::default(), 0o644, 0, 0).unwrap();
+        db.set(&"count", &"42", 0).unwrap();
+
+        let key = "count";
+        let lock = db.lock(&key, 0).unwrap();
+        assert_eq!(lock.map(|v| v.len()), Some(2));
+        let count = lock.map(|v| str::from_utf8(v).unwrap().parse::<i32>().unwrap());
+        assert_eq!(count, Some(42));
+
+        let missing = "missing";
+        assert_eq!(db.lock(&missing, 0).unwrap().map(|v| v.len()), None);
+
+        let _ = remove_file(path);
+    }
+
+    #[test]
+    fn test_store_transaction() {
+        let path = Path::new("test_store_transaction.db");
+        let _ = remove_file(path);
+        let db = MDBM::new(path, Default::default(), 0o644, 4096, 0).unwrap();
+        db.set(&"first", &"before", 0).unwrap();
+
+        db.store_transaction(vec![("first", "one"), ("second", "two")])
+            .unwrap();
+        assert_eq!(db.fetch(&"first").unwrap().as_deref(), Some(&b"one"[..]));
+        assert_eq!(db.fetch(&"second").unwrap().as_deref(), Some(&b"two"[..]));
+
+        // Too big for a page without large objects, so the second store fails.
+        let too_big = vec![0u8; 8192];
+        let entries: Vec<(&[u8], &[u8])> = vec![
+            (b"first", b"changed"),
+            (b"new", &too_big),
+            (b"third", b"three"),
+        ];
+        assert!(db.store_transaction(entries).is_err());
+        assert_eq!(db.fetch(&"first").unwrap().as_deref(), Some(&b"one"[..]));
+        assert_eq!(db.fetch(&"new").unwrap(), None);
+        assert_eq!(db.fetch(&"third").unwrap(), None);
+
+        let _ = remove_file(path);
+    }
+
+    #[test]
+    fn test_preload_keys() {
+        let path = Path::new("test_preload_keys.db");
+        let _ = remove_file(path);
+        let db = MDBM::new(path, Default::default(), 0o644, 0, 0).unwrap();
+        for i in 0..100 {
+            db.set(
+                format!("key{}", i).as_str(),
+                format!("value{}", i).as_str(),
+                0,
+            )
+            .unwrap();
+        }
+
+        let hot = vec![
+            "key3".to_string(),
+            "key42".to_string(),
+            "missing".to_string(),
+        ];
+        db.preload_keys(&hot).unwrap();
+        assert_eq!(db.fetch(&"key3").unwrap().as_deref(), Some(&b"value3"[..]));
+        assert_eq!(
+            db.fetch(&"key42").unwrap().as_deref(),
+            Some(&b"value42"[..])
+        );
+
+        let _ = remove_file(path);
+    }
+
+    #[test]
+    fn test_window_auto_grow() {
+        let path = Path::new("test_window_auto_grow.db");
+        let _ = remove_file(path);
+        let opts = super::Options {
+            large_objects: true,
+            ..Default::default()
+        };
+        let initial = 64 * 1024;
+        // Nothing borrowed from the database outlives a single call here.
+        let db = unsafe { MDBM::open_windowed(path, opts, 0o644, 4096, 0, initial) }.unwrap();
+
+        let value = vec![9u8; 16 * 1024];
+        for i in 0..64 {
+            db.set(format!("key{}", i).as_str(), &value[..], 0).unwrap();
+        }
+        db.set_window_auto_grow(initial, 4 * 1024 * 1024, 2.0)
+            .unwrap();
+
+        // Cycling through 1MB of large objects thrashes a 64KB window.
+        for _ in 0..8 {
+            for i in 0..64 {
+                let got = db.fetch(format!("key{}", i).as_str()).unwrap();
+                assert_eq!(got.as_deref(), Some(&value[..]));
+            }
+        }
+        let stats = db.window_stats().unwrap();
+        assert!(stats.window_size as usize > initial, "{:?}", stats);
+
+        let _ = remove_file(path);
+    }
+
+    #[test]
+    fn test_fetch_fixed() {
+        let path = Path::new("test_fetch_fixed.db");
+        let _ = remove_file(path);
+        let db = MDBM::new(path, Default::default(), 0o644, 0, 0).unwrap();
+        db.set(&"right", &[1u8, 2, 3, 4], 0).unwrap();
+        db.set(&"wrong", &[1u8, 2, 3], 0).unwrap();
+
+        assert_eq!(
+            db.fetch_fixed::<_, 4>(&"right").unwrap(),
+            Some([1, 2, 3, 4])
+        );
+        let err = db.fetch_fixed::<_, 4>(&"wrong").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(db.fetch_fixed::<_, 4>(&"missing").unwrap(), None);
+
+        let _ = remove_file(path);
+    }
+
+    #[test]
+    fn test_evaluate_hashes() {
+        let keys: Vec<String> = (0..100).map(|i| format!("user:{}", i)).collect();
+        let keys: Vec<&[u8]> = keys.iter().map(|k| k.as_bytes()).collect();
+
+        let scores = MDBM::evaluate_hashes(&keys);
+        let mut seen: Vec<u32> = scores.iter().map(|&(h, _)| h.hash_constant()).collect();
+        seen.sort();
+        let mut all: Vec<u32> = super::HashFunction::ALL
+            .iter()
+            .map(|h| h.hash_constant())
+            .collect();
+        all.sort();
+        assert_eq!(seen, all);
+
+        assert!(scores.windows(2).all(|w| w[0].1 <= w[1].1));
+        assert!(scores.iter().all(|&(_, score)| (0.0..1.0).contains(&score)));
+    }
+
+    #[test]
+    fn test_owned_datum() {
+        let path = Path::new("test_owned_datum.db");
+        let _ = remove_file(path);
+        let db = MDBM::new(path, Default::default(), 0o644, 0, 0).unwrap();
+        let large = vec![9u8; 100];
+        db.set(&"small", &"tiny", 0).unwrap();
+        db.set(&"exact", &[1u8; 22], 0).unwrap();
+        db.set(&"large", &large, 0).unwrap();
+        db.set(&"empty", &"", 0).unwrap();
+
+        let small = db.fetch(&"small").unwrap().unwrap();
+        assert_eq!(&*small, b"tiny");
+        assert_eq!(&*db.fetch(&"exact").unwrap().unwrap(), &[1u8; 22][..]);
+        let fetched = db.fetch(&"large").unwrap().unwrap();
+        assert_eq!(&*fetched, &large[..]);
+        assert_eq!(fetched.into_vec(), large);
+        assert!(db.fetch(&"empty").unwrap().unwrap().is_empty());
+        assert_eq!(small.clone().into_vec(), b"tiny".to_vec());
+
+        let _ = remove_file(path);
+    }
+
+    #[test]
+    fn test_try_lock_db() {
+        use std::sync::mpsc;
+        use std::thread;
+
+        let path = "test_try_lock_db.db";
+        let _ = remove_file(path);
+        let db = MDBM::new(path, Default::default(), 0o644, 0, 0).unwrap();
+
+        let (locked_tx, locked_rx) = mpsc::channel();
+        let (done_tx, done_rx) = mpsc::channel::<()>();
+        let holder = thread::spawn(move || {
+            let db = MDBM::new(path, Default::default(), 0o644, 0, 0).unwrap();
+            let _lock = db.try_lock_db().unwrap().unwrap();
+            locked_tx.send(()).unwrap();
+            let _ = done_rx.recv();
+        });
+        locked_rx.recv().unwrap();
+        assert!(db.try_lock_db().unwrap().is_none());
+
+        done_tx.send(()).unwrap();
+        holder.join().unwrap();
+        assert!(db.try_lock_db().unwrap().is_some());
+
+        let _ = remove_file(path);
+    }
+
+    #[test]
+    fn test_iter_sorted_be_u64() {
+        let path = Path::new("test_iter_sorted_be_u64.db");
+        let _ = remove_file(path);
+        let db = MDBM::new(path, Default::default(), 0o644, 0, 0).unwrap();
+        for &n in &[300u64, 7, u64::MAX, 0, 42] {
+            db.set(&n.to_be_bytes(), format!("{}", n).as_str(), 0)
+                .unwrap();
+        }
+        db.set(&"not eight", &"skipped", 0).unwrap();
+
+        let sorted = db.iter_sorted_be_u64();
+        let keys: Vec<u64> = sorted.iter().map(|&(k, _)| k).collect();
+        assert_eq!(keys, vec![0, 7, 42, 300, u64::MAX]);
+        assert_eq!(sorted[3].1, b"300".to_vec());
+
+        let _ = remove_file(path);
+    }
+
+    #[test]
+    fn test_memory_usage() {
+        let path = Path::new("test_memory_usage.db");
+        let _ = remove_file(path);
+        let db = MDBM::new(path, Default::default(), 0o644, 0, 0).unwrap();
+        for i in 0..100 {
+            db.set(format!("key{}", i).as_str(), &"value", 0).unwrap();
+        }
+
+        let usage = db.memory_usage();
+        assert!(usage.mapped_bytes > 0);
+        assert!(usage.resident_bytes <= usage.mapped_bytes);
+
+        let _ = remove_file(path);
+    }
+
+    #[test]
+    fn test_store_with_ttl() {
+        use std::thread;
+        use std::time::Duration;
+
+        let path = Path::new("test_store_with_ttl.db");
+        let _ = remove_file(path);
+        let db = MDBM::new(path, Default::default(), 0o644, 0, 0).unwrap();
+
+        db.store_with_ttl(&"short", &"value", Duration::from_millis(50))
+            .unwrap();
+        db.store_with_ttl(&"long", &"value", Duration::from_secs(3600))
+            .unwrap();
+        assert_eq!(
+            db.fetch_live(&"short").unwrap().as_deref(),
+            Some(&b"value"[..])
+        );
+
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(db.fetch_live(&"short").unwrap(), None);
+        // Lazily deleted by the fetch above.
+        assert_eq!(db.fetch(&"short").unwrap(), None);
+        assert_eq!(
+            db.fetch_live(&"long").unwrap().as_deref(),
+            Some(&b"value"[..])
+        );
+
+        db.set(&"plain", &"abc", 0).unwrap();
+        let err = db.fetch_live(&"plain").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        let _ = remove_file(path);
+    }
+
+    #[test]
+    fn test_sweep_expired() {
+        use std::thread;
+        use std::time::Duration;
+
+        let path = Path::new("test_sweep_expired.db");
+        let _ = remove_file(path);
+        let db = MDBM::new(path, Default::default(), 0o644, 0, 0).unwrap();
+        // Not a TTL entry, though its value reads as long expired.
+        db.set(&"plain", &[0u8; 8][..], 0).unwrap();
+        for i in 0..10 {
+            let ttl = if i % 2 == 0 {
+                Duration::from_millis(10)
+            } else {
+                Duration::from_secs(3600)
+            };
+            db.store_with_ttl(format!("ttl:key{}", i).as_str(), &"value", ttl)
+                .unwrap();
+        }
+        thread::sleep(Duration::from_millis(50));
+
+        assert_eq!(db.sweep_expired(b"ttl:").unwrap(), 5);
+        let mut keys: Vec<_> = db.iter_with_values().map(|(k, _)| k).collect();
+        keys.sort();
+        let mut live = vec![b"plain".to_vec()];
+        live.extend(
+            (0..10)
+                .filter(|i| i % 2 == 1)
+                .map(|i| format!("ttl:key{}", i).into_bytes()),
+        );
+        assert_eq!(keys, live);
+        assert_eq!(db.sweep_expired(b"ttl:").unwrap(), 0);
+
+        let _ = remove_file(path);
+    }
+
+    #[test]
+    fn test_iter_dups() {
+        use super::StoreMode;
+
+        let path = Path::new("test_iter_dups.db");
+        let _ = remove_file(path);
+        let db = MDBM::new(path, Default::default(), 0o644, 0, 0).unwrap();
+        for value in &["red", "green", "blue"] {
+            db.set(&"tag", *value, StoreMode::InsertDuplicate.flag())
+                .unwrap();
+        }
+        db.set(&"other", &"value", 0).unwrap();
+
+        let key = "tag";
+        let lock = db.lock(&key, 0).unwrap();
+        let mut dups = lock.iter_dups();
+        assert!(dups.next().is_some());
+        let mut values: Vec<&[u8]> = lock.iter_dups().collect();
+        values.sort();
+        assert_eq!(values, vec![&b"blue"[..], b"green", b"red"]);
+
+        let missing = "missing";
+        assert_eq!(db.lock(&missing, 0).unwrap().iter_dups().count(), 0);
+
+        let _ = remove_file(path);
+    }
+
+    #[test]
+    fn test_inter_process() {
+        use std::env;
+        use std::process::Command;
+
+        const CHILD_VAR: &str = "RUST_MDBM_INTER_PROCESS_CHILD";
+        let opts = super::Options {
+            inter_process: true,
+            ..Default::default()
+        };
+
+        // Rerun as the child: read what the parent wrote.
+        if let Ok(path) = env::var(CHILD_VAR) {
+            let db = MDBM::new(path, opts, 0o644, 0, 0).unwrap();
+            assert_eq!(
+                db.fetch(&"key").unwrap().as_deref(),
+                Some(&b"from parent"[..])
+            );
+            return;
+        }
+
+        let path = Path::new("test_inter_process.db");
+        let _ = remove_file(path);
+        let db = MDBM::new(path, opts, 0o644, 0, 0).unwrap();
+        db.set(&"key", &"from parent", 0).unwrap();
+
+        let status = Command::new(env::current_exe().unwrap())
+            .args(&["tests::test_inter_process", "--exact"])
+            .env(CHILD_VAR, path)
+            .status()
+            .unwrap();
+        assert!(status.success());
+
+        let _ = remove_file(path);
+    }
+
+    #[test]
+    fn test_multimap() {
+        use super::StoreMode;
+
+        let path = Path::new("test_multimap.db");
+        let _ = remove_file(path);
+        let mut db = MDBM::new(path, Default::default(), 0o644, 0, 0).unwrap();
+        db.set_default_store_mode(StoreMode::InsertDuplicate);
+
+        for tag in &["a", "b", "c"] {
+            db.put(&"post:1", *tag).unwrap();
+        }
+        let mut tags: Vec<Vec<u8>> = db
+            .get_all(&"post:1")
+            .unwrap()
+            .into_iter()
+            .map(|v| v.into_vec())
+            .collect();
+        tags.sort();
+        assert_eq!(tags, vec![b"a".to_vec(), b"b".to_vec(), b"c".to_vec()]);
+        assert!(db.get_all(&"post:2").unwrap().is_empty());
+
+        let _ = remove_file(path);
+    }
+
+    #[test]
+    fn test_verify_store() {
+        let path = Path::new("test_verify_store.db");
+        let _ = remove_file(path);
+        let db = MDBM::new(path, Default::default(), 0o644, 0, 0).unwrap();
+
+        db.verify_store(&"key", &"value").unwrap();
+        db.verify_store(&"key", &[0u8, 1, 2, 255]).unwrap();
+        db.verify_store(&"empty", &"").unwrap();
+        assert_eq!(
+            db.fetch(&"key").unwrap().as_deref(),
+            Some(&[0u8, 1, 2, 255][..])
+        );
+
+        let _ = remove_file(path);
+    }
+
+    #[test]
+    fn test_into_raw_from_raw() {
+        let path = Path::new("test_into_raw.db");
+        let _ = remove_file(path);
+        let db = MDBM::new(path, Default::default(), 0o644, 0, 0).unwrap();
+        db.set(&"key", &"value", 0).unwrap();
+
+        let raw = db.into_raw();
+        assert!(!raw.is_null());
+        let db = unsafe { MDBM::from_raw(raw) };
+
+        assert_eq!(db.fetch(&"key").unwrap().as_deref(), Some(&b"value"[..]));
+        db.set(&"other", &"more", 0).unwrap();
+        assert_eq!(db.fetch(&"other").unwrap().as_deref(), Some(&b"more"[..]));
+        db.close().unwrap();
+        let _ = remove_file(path);
+    }
+
+    #[test]
+    fn test_repeated_opens() {
+        // Each open used to leak its path; opening over and over should
+        // keep working without building up garbage.
+        let path = Path::new("test_repeated_opens.db");
+        let _ = remove_file(path);
+        MDBM::new(path, Default::default(), 0o644, 0, 0)
+            .unwrap()
+            .set(&"key", &"value", 0)
+            .unwrap();
+
+        for _ in 0..1000 {
+            let db = MDBM::new(path, Default::default(), 0o644, 0, 0).unwrap();
+            assert_eq!(db.fetch(&"key").unwrap().as_deref(), Some(&b"value"[..]));
+            assert_eq!(db.path(), path);
+        }
+
+        let _ = remove_file(path);
+    }
+
+    #[test]
+    fn test_store_large() {
+        let path = Path::new("test_store_large.db");
+        let _ = remove_file(path);
+        let opts = super::Options {
+            large_objects: true,
+            ..Default::default()
+        };
+        let db = MDBM::new(path, opts, 0o644, 0, 0).unwrap();
+        let threshold = db.get_stats().unwrap().s_large_threshold;
+
+        db.store_large(&"small", &"tiny", 0).unwrap();
+        assert_eq!(db.fetch(&"small").unwrap().as_deref(), Some(&b"tiny"[..]));
+        let stats = db.get_stats().unwrap();
+        assert_eq!(stats.s_large_num_entries, 1);
+        assert_eq!(stats.s_large_threshold, threshold);
+
+        let _ = remove_file(path);
+
+        let path = Path::new("test_store_large_disabled.db");
+        let _ = remove_file(path);
+        let db = MDBM::new(path, Default::default(), 0o644, 0, 0).unwrap();
+        let err = db.store_large(&"small", &"tiny", 0).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        let _ = remove_file(path);
+    }
+
+    #[test]
+    fn test_num_pages() {
+        let path = Path::new("test_num_pages.db");
+        let _ = remove_file(path);
+        let db = MDBM::new(path, Default::default(), 0o644, 0, 0).unwrap();
+
+        let pages = db.num_pages();
+        assert!(pages >= 1);
+        let dir_pages = db.num_dir_pages();
+
+        let value = [7u8; 200];
+        for i in 0..2000 {
+            db.set(format!("key{}", i).as_str(), &value[..], 0).unwrap();
+        }
+        assert!(db.num_pages() > pages);
+        assert!(db.num_dir_pages() >= dir_pages);
+
+        let _ = remove_file(path);
+    }
+
+    #[test]
+    fn test_lock_prefix() {
+        use std::thread;
+
+        let path = "test_lock_prefix.db";
+        let _ = remove_file(path);
+        let db = MDBM::new(path, Default::default(), 0o644, 0, 0).unwrap();
+        db.set(&"ns:a", &"0", 0).unwrap();
+        db.set(&"ns:b", &"0", 0).unwrap();
+
+        let reader = thread::spawn(move || {
+            let db = MDBM::new(path, Default::default(), 0o644, 0, 0).unwrap();
+            for _ in 0..200 {
+                let _lock = db.lock_prefix(b"ns:").unwrap();
+                let a = db.fetch(&"ns:a").unwrap();
+                let b = db.fetch(&"ns:b").unwrap();
+                assert_eq!(a, b);
+            }
+        });
+        for i in 1..200 {
+            let _lock = db.lock_prefix(b"ns:").unwrap();
+            let value = i.to_string();
+            db.set(&"ns:a", value.as_str(), 0).unwrap();
+            db.set(&"ns:b", value.as_str(), 0).unwrap();
+        }
+        reader.join().unwrap();
+
+        let _ = remove_file(path);
+    }
+
+    #[test]
+    fn test_lock_poisoning() {
+        let path = Path::new("test_lock_poisoning.db");
+        let _ = remove_file(path);
+        let db = MDBM::new(path, Default::default(), 0o644, 0, 0).unwrap();
+        db.set(&"key", &"value", 0).unwrap();
+
+        let panicked = ::std::panic::catch_unwind(::std::panic::AssertUnwindSafe(|| {
+            let _lock = db.lock(&"key", super::WRITE_LOCK).unwrap();
+            panic!("while holding the lock");
+        }));
+        assert!(panicked.is_err());
+
+        // A panic under a read lock can't have left a value half-written.
+        let panicked = ::std::panic::catch_unwind(::std::panic::AssertUnwindSafe(|| {
+            let _lock = db.lock(&"read", 0).unwrap();
+            panic!("while holding a read lock");
+        }));
+        assert!(panicked.is_err());
+        db.lock(&"read", 0).unwrap();
+
+        // Other keys, and the same key in other files, are unaffected.
+        db.lock(&"other", 0).unwrap();
+        let other_path = Path::new("test_lock_poisoning_other.db");
+        let _ = remove_file(other_path);
+        let other = MDBM::new(other_path, Default::default(), 0o644, 0, 0).unwrap();
+        other.lock(&"key", 0).unwrap();
+
+        match db.lock(&"key", 0) {
+            Err(super::LockError::Poisoned(poisoned)) => {
+                assert_eq!(poisoned.get_ref().get(), Some(&b"value"[..]));
+                let lock = poisoned.into_inner();
+                assert_eq!(lock.get(), Some(&b"value"[..]));
+            }
+            result => panic!("expected a poisoned lock, got {:?}", result.err()),
+        }
+        assert!(db.try_lock(&"key", 0).is_err());
+        assert!(db.fetch(&"key").is_err());
+        // A second handle on the same file sees the poison too.
+        let dup = db.dup_handle().unwrap();
+        assert!(dup.lock(&"key", 0).is_err());
+
+        db.clear_poison(&"key").unwrap();
+        assert_eq!(db.lock(&"key", 0).unwrap().get(), Some(&b"value"[..]));
+
+        let _ = remove_file(path);
+        let _ = remove_file(other_path);
+    }
+
+    #[test]
+    fn test_presize_bytes() {
+        let path = Path::new("test_presize_bytes.db");
+        let _ = remove_file(path);
+        let opts = super::Options {
+            presize_bytes: Some(1 << 20),
+            ..Default::default()
+        };
+        let db = MDBM::new(path, opts, 0o644, 0, 0).unwrap();
+        assert!(db.memory_usage().mapped_bytes >= 1 << 20);
+
+        assert_eq!(super::presize_for_bytes(5000, 4096).unwrap(), 8192);
+        assert_eq!(super::presize_for_bytes(4096, 0).unwrap(), 4096);
+        let err = super::presize_for_bytes(u64::MAX, 0).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+
+        let _ = remove_file(path);
+    }
+
+    #[test]
+    fn test_get_ref() {
+        use std::sync::mpsc;
+        use std::thread;
+
+        let path = "test_get_ref.db";
+        let _ = remove_file(path);
+        let db = MDBM::new(path, Default::default(), 0o644, 0, 0).unwrap();
+        db.set(&"key", &"value", 0).unwrap();
+        assert!(db.get_ref(&"missing").unwrap().is_none());
+
+        let guard = db.get_ref(&"key").unwrap().unwrap();
+        assert_eq!(&*guard, b"value");
+        assert_eq!(guard.as_ref(), b"value");
+
+        let (checked_tx, checked_rx) = mpsc::channel();
+        let (dropped_tx, dropped_rx) = mpsc::channel::<()>();
+        let other = thread::spawn(move || {
+            let db = MDBM::new(path, Default::default(), 0o644, 0, 0).unwrap();
+            let key = "key";
+            let while_held = db.try_lock(&key, super::WRITE_LOCK).unwrap().is_some();
+            checked_tx.send(while_held).unwrap();
+            dropped_rx.recv().unwrap();
+            let lock = db.try_lock(&key, super::WRITE_LOCK).unwrap();
+            lock.is_some()
+        });
+        assert!(!checked_rx.recv().unwrap());
+        drop(guard);
+        dropped_tx.send(()).unwrap();
+        assert!(other.join().unwrap());
+
+        let _ = remove_file(path);
+    }
+
+    #[test]
+    fn test_delete_batch() {
+        let path = Path::new("test_delete_batch.db");
+        let _ = remove_file(path);
+        let db = MDBM::new(path, Default::default(), 0o644, 0, 0).unwrap();
+        db.set(&"a", &"1", 0).unwrap();
+        db.set(&"b", &"2", 0).unwrap();
+        db.set(&"c", &"3", 0).unwrap();
+
+        let removed = db.delete_batch(&["a", "missing", "c", "gone"]).unwrap();
+        assert_eq!(removed, 2);
+        assert!(db.fetch(&"a").unwrap().is_none());
+        assert_eq!(db.fetch(&"b").unwrap().as_deref(), Some(&b"2"[..]));
+        assert!(db.fetch(&"c").unwrap().is_none());
+        assert_eq!(db.delete_batch(&["a", "c"]).unwrap(), 0);
+
+        let _ = remove_file(path);
+    }
+
+    #[test]
+    fn test_last_access() {
+        use std::time::{Duration, SystemTime};
+
+        let path = Path::new("test_last_access.db");
+        let _ = remove_file(path);
+        let db = MDBM::new(path, Default::default(), 0o644, 0, 0).unwrap();
+        db.set(&"key", &"value", 0).unwrap();
+        db.enable_access_time().unwrap();
+
+        let before = SystemTime::now() - Duration::from_secs(1);
+        db.fetch(&"key").unwrap();
+        let accessed = db.last_access().unwrap();
+        assert!(accessed >= before, "{:?} is before {:?}", accessed, before);
+        assert!(accessed <= SystemTime::now());
+
+        let _ = remove_file(path);
+    }
+
+    #[test]
+    fn test_update() {
+        let path = Path::new("test_update.db");
+        let _ = remove_file(path);
+        let db = MDBM::new(path, Default::default(), 0o644, 0, 0).unwrap();
+        db.set(&"key", &"old", 0).unwrap();
+
+        db.update(&"key", &"new").unwrap();
+        assert_eq!(db.fetch(&"key").unwrap().as_deref(), Some(&b"new"[..]));
+
+        let err = db.update(&"missing", &"value").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        assert!(db.fetch(&"missing").unwrap().is_none());
+
+        let _ = remove_file(path);
+    }
 
-fn to_raw_datum(datum: &Datum) -> mdbm_sys::datum {
-    mdbm_sys::datum {
-        dptr: datum.bytes.as_ptr() as *mut _,
-        dsize: datum.bytes.len() as libc::c_int,
+    #[test]
+    fn test_fetch_located() {
+        let path = Path::new("test_fetch_located.db");
+        let _ = remove_file(path);
+        let db = MDBM::new(path, Default::default(), 0o644, 0, 0).unwrap();
+        let value = [7u8; 200];
+        for i in 0..500 {
+            db.set(format!("key{}", i).as_str(), &value[..], 0).unwrap();
+        }
+
+        for i in 0..500 {
+            let key = format!("key{}", i);
+            let (fetched, page) = db.fetch_located(key.as_str()).unwrap().unwrap();
+            assert_eq!(&*fetched, &value[..]);
+            assert_eq!(page, db.page_for_key(key.as_str()).unwrap());
+        }
+        assert!(db.fetch_located(&"missing").unwrap().is_none());
+
+        let _ = remove_file(path);
     }
-}
 
-pub struct Lock<'a> {
-    db: &'a MDBM,
-    key: Datum<'a>,
-}
+    #[test]
+    fn test_clone_to() {
+        let path = Path::new("test_clone_to.db");
+        let clone_path = Path::new("test_clone_to_clone.db");
+        let _ = remove_file(path);
+        let _ = remove_file(clone_path);
+        let db = MDBM::new(path, Default::default(), 0o644, 0, 0).unwrap();
+        db.set(&"a", &"1", 0).unwrap();
+        db.set(&"b", &"2", 0).unwrap();
 
-impl<'a> Lock<'a> {
-    /// Fetch a key.
-    pub fn get(&'a self) -> Option<&'a [u8]> {
-        unsafe {
-            let value = mdbm_sys::mdbm_fetch(self.db.db, to_raw_datum(&self.key));
+        let clone = db.clone_to(clone_path).unwrap();
+        assert_eq!(clone.fetch(&"a").unwrap().as_deref(), Some(&b"1"[..]));
+        clone.set(&"a", &"changed", 0).unwrap();
+        clone.delete(&"b").unwrap();
+        clone.set(&"c", &"3", 0).unwrap();
 
-            if value.dptr.is_null() {
-                None
-            } else {
-                // Cast pointer from signed char (c) to unsigned char (rust)
-                let u8_ptr: *const u8 = mem::transmute::<*mut i8, *const u8>(value.dptr);
-                Some(slice::from_raw_parts(u8_ptr, value.dsize as usize))
-            }
-        }
+        assert_eq!(db.fetch(&"a").unwrap().as_deref(), Some(&b"1"[..]));
+        assert_eq!(db.fetch(&"b").unwrap().as_deref(), Some(&b"2"[..]));
+        assert!(db.fetch(&"c").unwrap().is_none());
+
+        let _ = remove_file(path);
+        let _ = remove_file(clone_path);
     }
-}
 
-impl<'a> Drop for Lock<'a> {
-    fn drop(&mut self) {
-        unsafe {
-            let rc = mdbm_sys::mdbm_unlock_smart(self.db.db, &to_raw_datum(&self.key), 0);
+    #[test]
+    fn test_store_from_reader() {
+        let path = Path::new("test_store_from_reader.db");
+        let _ = remove_file(path);
+        let opts = super::Options {
+            large_objects: true,
+            ..Default::default()
+        };
+        let db = MDBM::new(path, opts, 0o644, 0, 0).unwrap();
 
-            assert_eq!(rc, 1);
+        let value: Vec<u8> = (0..1 << 20).map(|i| (i % 251) as u8).collect();
+        db.store_from_reader(&"big", io::Cursor::new(&value), value.len())
+            .unwrap();
+        assert_eq!(db.fetch(&"big").unwrap().unwrap().into_vec(), value);
+
+        let err = db
+            .store_from_reader(&"short", io::Cursor::new(&value[..10]), 20)
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+        assert!(db.fetch(&"short").unwrap().is_none());
+
+        let _ = remove_file(path);
+    }
+
+    #[test]
+    fn test_config() {
+        use super::{Alignment, CacheMode, HashFunction};
+        use std::ptr;
+
+        let path = Path::new("test_config.db");
+        let _ = remove_file(path);
+        let opts = super::Options {
+            hash: Some(HashFunction::FNV),
+            large_objects: true,
+            ..Default::default()
+        };
+        let db = MDBM::new(path, opts, 0o644, 8192, 0).unwrap();
+
+        let config = db.config();
+        assert_eq!(config.page_size, 8192);
+        assert_eq!(
+            config.hash.map(|h| h.hash_constant()),
+            Some(HashFunction::FNV.hash_constant())
+        );
+        assert!(config.large_objects);
+        assert_eq!(config.cache_mode, None);
+        assert_eq!(config.limit_size, None);
+
+        db.set_alignment(Alignment::Bits64).unwrap();
+        db.set_cache_mode(CacheMode::Lfu).unwrap();
+        let rc = unsafe { mdbm_sys::mdbm_limit_size_v3(db.as_raw(), 4, None, ptr::null_mut()) };
+        assert_eq!(rc, 0);
+
+        let config = db.config();
+        assert_eq!(config.alignment, Some(Alignment::Bits64));
+        assert_eq!(config.cache_mode, Some(CacheMode::Lfu));
+        assert_eq!(config.limit_size, Some(4 * 8192));
+
+        let _ = remove_file(path);
+    }
+
+    #[test]
+    fn test_validate() {
+        let path = Path::new("test_validate.db");
+        let _ = remove_file(path);
+        let db = MDBM::new(path, Default::default(), 0o644, 0, 0).unwrap();
+        db.set(&"good", &"text", 0).unwrap();
+        db.set(&"bad", &[0xffu8, 0xfe], 0).unwrap();
+        db.set(&"worse", &[b'a', 0x80], 0).unwrap();
+        db.set(&"fine", &"", 0).unwrap();
+
+        let mut failures = db
+            .validate(|_, value| str::from_utf8(value).map(|_| ()).map_err(|e| e.to_string()))
+            .unwrap();
+        failures.sort();
+        let keys: Vec<&[u8]> = failures.iter().map(|(k, _)| &k[..]).collect();
+        assert_eq!(keys, vec![&b"bad"[..], &b"worse"[..]]);
+        assert!(failures[0].1.contains("utf-8"), "{}", failures[0].1);
+
+        let _ = remove_file(path);
+    }
+
+    #[test]
+    fn test_prefetch_page() {
+        let path = Path::new("test_prefetch_page.db");
+        let _ = remove_file(path);
+        let db = MDBM::new(path, Default::default(), 0o644, 0, 0).unwrap();
+        for i in 0..2000 {
+            db.set(
+                format!("key{}", i).as_str(),
+                format!("value{}", i).as_str(),
+                0,
+            )
+            .unwrap();
+        }
+
+        let mut pages: Vec<u32> = (0..2000)
+            .map(|i| db.page_for_key(format!("key{}", i).as_str()).unwrap())
+            .collect();
+        pages.sort();
+        pages.dedup();
+        for &page in &pages {
+            db.prefetch_page(page).unwrap();
+        }
+        let err = db.prefetch_page(u32::MAX).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+
+        let mut seen = 0;
+        for (key, value) in db.iter_with_values() {
+            let key = str::from_utf8(&key).unwrap();
+            assert_eq!(value, format!("value{}", &key[3..]).into_bytes());
+            seen += 1;
         }
+        assert_eq!(seen, 2000);
+
+        let _ = remove_file(path);
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::MDBM;
-    use std::fs::remove_file;
-    use std::path::Path;
-    use std::str;
+    #[test]
+    fn test_release_cached_pages() {
+        let path = Path::new("test_release_cached_pages.db");
+        let _ = remove_file(path);
+        let db = MDBM::new(path, Default::default(), 0o644, 0, 0).unwrap();
+        for i in 0..2000 {
+            db.set(
+                format!("key{}", i).as_str(),
+                format!("value{}", i).as_str(),
+                0,
+            )
+            .unwrap();
+        }
+        assert_eq!(db.iter_with_values().count(), 2000);
+
+        db.release_cached_pages().unwrap();
+        for i in 0..2000 {
+            assert_eq!(
+                db.fetch(format!("key{}", i).as_str()).unwrap().as_deref(),
+                Some(format!("value{}", i).as_bytes())
+            );
+        }
+
+        let _ = remove_file(path);
+    }
 
     #[test]
-    fn test_set_get() {
-        let path = Path::new("test.db");
-        let db = MDBM::new(&path, Default::default(), 0o644, 0, 0).unwrap();
+    fn test_parse_shared_mapping() {
+        use super::parse_shared_mapping;
 
-        db.set(&"hello", &"world", 0).unwrap();
+        // 08:01 is device 0x801.
+        let shared = "7f00a000-7f00c000 rw-s 00002000 08:01 1234 /tmp/x.db";
+        assert_eq!(
+            parse_shared_mapping(shared, 0x801, 1234),
+            Some((0x7f00a000, 0x2000, 0x2000))
+        );
+        // Same inode on another filesystem.
+        assert_eq!(parse_shared_mapping(shared, 0x802, 1234), None);
+        // A private, copy-on-write mapping of the same file.
+        let private = "7f00a000-7f00c000 rw-p 00002000 08:01 1234 /tmp/x.db";
+        assert_eq!(parse_shared_mapping(private, 0x801, 1234), None);
+    }
 
-        // key needs to be an lvalue so the lock can hold a reference to
-        // it.
-        let key = "hello";
+    #[test]
+    fn test_open_copy() {
+        let path = Path::new("test_open_copy.db");
+        let _ = remove_file(path);
+        {
+            let db = MDBM::new(path, Default::default(), 0o644, 0, 0).unwrap();
+            db.set(&"a", &"1", 0).unwrap();
+            db.set(&"b", &"2", 0).unwrap();
+        }
+        let original = std::fs::read(path).unwrap();
 
-        // Lock the key. RIAA will unlock it when we exit this scope.
-        let value = db.lock(&key, 0).unwrap();
+        let copy = MDBM::open_copy(path).unwrap();
+        assert!(!copy.path().exists());
+        assert_eq!(copy.fetch(&"a").unwrap().as_deref(), Some(&b"1"[..]));
+        copy.set(&"a", &"changed", 0).unwrap();
+        copy.delete(&"b").unwrap();
+        copy.set(&"c", &"3", 0).unwrap();
+        drop(copy);
 
-        // Convert the value into a string. The lock is still live at this
-        // point.
-        let value = str::from_utf8(value.get().unwrap()).unwrap();
-        assert_eq!(value, "world");
-        println!("hello: {}", value);
+        assert_eq!(std::fs::read(path).unwrap(), original);
 
         let _ = remove_file(path);
     }
 
     #[test]
-    fn test_read_only() {
-        let path = Path::new("test_rw.db");
-        let mut opts: super::Options = Default::default();
-        opts.reads = super::ReadState::WriteOnly;
-        opts.hash = Some(super::HashFunction::JENKINS);
+    fn test_hash_function_as_key() {
+        use super::{HashFunction, ReadState};
+        use std::collections::{BTreeSet, HashSet};
 
-        let db = MDBM::new(&path, opts, 0o644, 0, 0).unwrap();
+        let mut hashes: HashSet<HashFunction> = HashFunction::ALL.iter().cloned().collect();
+        hashes.insert(HashFunction::MAX);
+        hashes.insert(HashFunction::FNV);
+        assert_eq!(hashes.len(), 12);
 
-        db.set(&"hello", &"world", 0).unwrap();
+        let states: BTreeSet<ReadState> = [
+            ReadState::ReadWrite,
+            ReadState::ReadOnly,
+            ReadState::WriteOnly,
+            ReadState::ReadOnly,
+        ]
+        .iter()
+        .cloned()
+        .collect();
+        assert_eq!(states.len(), 3);
+        assert_eq!(states.iter().next(), Some(&ReadState::ReadOnly));
+    }
 
-        //// Strangely enough, this doesn't fail
-        // let err = db.lock(&"hello", 0);
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_options_from_json() {
+        use super::{HashFunction, Options, ReadState};
 
-        // match err {
-        //     Ok(value) => assert!(
-        //         false,
-        //         "WriteOnly should error on read, instead got {}",
-        //         value
-        //             .get()
-        //             .and_then(|v| Some(str::from_utf8(v).unwrap_or("utf8 failure")))
-        //             .unwrap_or("none"),
-        //     ),
-        //     Err(_) => assert!(true),
-        // }
+        let json = r#"{"reads": "read_write", "hash": "jenkins", "large_objects": true}"#;
+        let opts: Options = serde_json::from_str(json).unwrap();
+        assert_eq!(opts.reads, ReadState::ReadWrite);
+        assert_eq!(opts.hash, Some(HashFunction::JENKINS));
+        assert!(opts.large_objects);
+        assert!(opts.create);
 
-        opts.reads = super::ReadState::ReadOnly;
-        let db = MDBM::new(&path, opts, 0o644, 0, 0).unwrap();
-        let err = db.set(&"another", &"world", 0);
-        match err {
-            Ok(_) => assert!(false, "ReadOnly should error on Write"),
-            Err(_) => assert!(true),
+        let path = Path::new("test_options_from_json.db");
+        let _ = remove_file(path);
+        let db = MDBM::new(path, opts, 0o644, 0, 0).unwrap();
+        assert_eq!(db.get_hash(), Some(HashFunction::JENKINS));
+        db.set(&"key", &"value", 0).unwrap();
+
+        let _ = remove_file(path);
+    }
+
+    #[test]
+    fn test_report() {
+        let path = Path::new("test_report.db");
+        let _ = remove_file(path);
+        let db = MDBM::new(path, Default::default(), 0o644, 0, 0).unwrap();
+        let empty = db.report();
+        assert!(empty.contains("records: 0\n"), "{}", empty);
+        assert!(!empty.contains("most-filled"), "{}", empty);
+
+        let value = [7u8; 200];
+        for i in 0..300 {
+            db.set(format!("key{}", i).as_str(), &value[..], 0).unwrap();
         }
+        let report = db.report();
+        assert!(report.contains("records: 300\n"), "{}", report);
+        assert!(
+            report.contains(&format!("pages: {}\n", db.num_pages())),
+            "{}",
+            report
+        );
+        assert!(report.contains("most-filled page: "), "{}", report);
 
-        // key needs to be an lvalue so the lock can hold a reference to
-        // it.
-        let key = "hello";
+        let _ = remove_file(path);
+    }
 
-        // Lock the key. RIAA will unlock it when we exit this scope.
-        let value = db.lock(&key, 0).unwrap();
+    #[test]
+    fn test_fetch_str() {
+        use super::StoreMode;
 
-        // Convert the value into a string. The lock is still live at this
-        // point.
-        let value = str::from_utf8(value.get().unwrap()).unwrap();
-        assert_eq!(value, "world");
-        println!("hello: {}", value);
+        let path = Path::new("test_fetch_str.db");
+        let _ = remove_file(path);
+        let db = MDBM::new(path, Default::default(), 0o644, 0, 0).unwrap();
+
+        db.store_str(&"greeting", "héllo", StoreMode::Replace)
+            .unwrap();
+        assert_eq!(
+            db.fetch_str(&"greeting").unwrap(),
+            Some("héllo".to_string())
+        );
+        assert_eq!(db.fetch_str(&"missing").unwrap(), None);
+
+        let err = db
+            .store_str(&"greeting", "again", StoreMode::Insert)
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+
+        db.set(&"binary", &[0xffu8, 0xfe], 0).unwrap();
+        let err = db.fetch_str(&"binary").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        let _ = remove_file(path);
+    }
+
+    #[test]
+    fn test_store_flags() {
+        use super::{CacheMode, StoreFlags, StoreMode};
+
+        let path = Path::new("test_store_flags.db");
+        let _ = remove_file(path);
+        let db = MDBM::new(path, Default::default(), 0o644, 0, 0).unwrap();
+        db.set_cache_mode(CacheMode::Lru).unwrap();
+
+        let (insert, cache_only) = (StoreMode::Insert, StoreFlags::CACHE_ONLY);
+        assert!(db
+            .store_flags(&"key", &"first", insert, cache_only)
+            .unwrap());
+        assert!(!db
+            .store_flags(&"key", &"second", insert, cache_only)
+            .unwrap());
+        assert_eq!(db.fetch(&"key").unwrap().as_deref(), Some(&b"first"[..]));
+
+        assert!(db
+            .store_flags(&"key", &"third", StoreMode::Replace, StoreFlags::empty())
+            .unwrap());
+        assert_eq!(db.fetch(&"key").unwrap().as_deref(), Some(&b"third"[..]));
+
+        let err = db
+            .store_flags(&"key", &"value", StoreMode::Replace, StoreFlags::RESERVE)
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
 
         let _ = remove_file(path);
     }
 
-    // Tests that should fail to compile
+    #[test]
+    fn test_scoped_db() {
+        use super::ScopedDb;
+
+        let path = Path::new("test_scoped_db.db");
+        let _ = remove_file(path);
+        {
+            let db = ScopedDb::new(MDBM::new(path, Default::default(), 0o644, 0, 0).unwrap());
+            db.set(&"key", &"value", 0).unwrap();
+            assert_eq!(db.fetch(&"key").unwrap().as_deref(), Some(&b"value"[..]));
+            assert!(path.exists());
+        }
+        assert!(!path.exists());
+    }
 
-    /*
     #[test]
-    fn test_keys_cannot_escape() {
-        let db = MDBM::new(
-            &Path::new("test.db"),
-            super::MDBM_O_RDWR | super::MDBM_O_CREAT,
-            0o644,
-            0,
-            0
-        ).unwrap();
+    fn test_find() {
+        let path = Path::new("test_find.db");
+        let _ = remove_file(path);
+        let db = MDBM::new(path, Default::default(), 0o644, 0, 0).unwrap();
+        db.set(&"a", &"xray", 0).unwrap();
+        db.set(&"b", &"yankee", 0).unwrap();
+        db.set(&"c", &"xylophone", 0).unwrap();
+        db.set(&"d", &"", 0).unwrap();
 
-        db.set(&"hello", &"world", 0).unwrap();
+        let mut found = db.find(|_, value| value.first() == Some(&b'x')).unwrap();
+        found.sort();
+        assert_eq!(
+            found,
+            vec![
+                (b"a".to_vec(), b"xray".to_vec()),
+                (b"c".to_vec(), b"xylophone".to_vec()),
+            ]
+        );
+        assert!(db.find(|_, _| false).unwrap().is_empty());
+
+        let _ = remove_file(path);
+    }
 
-        let _ = {
-            let key = vec![1];
-            db.lock(&key.as_slice(), 0).unwrap()
+    #[test]
+    fn test_store_inline_only() {
+        let path = Path::new("test_store_inline_only.db");
+        let _ = remove_file(path);
+        let opts = super::Options {
+            large_objects: true,
+            ..Default::default()
         };
+        let db = MDBM::new(path, opts, 0o644, 4096, 0).unwrap();
+
+        db.store_inline_only(&"small", &"tiny", 0).unwrap();
+        assert_eq!(db.fetch(&"small").unwrap().as_deref(), Some(&b"tiny"[..]));
+
+        let big = vec![1u8; 4 * 4096];
+        let err = db.store_inline_only(&"big", &big[..], 0).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert!(db.fetch(&"big").unwrap().is_none());
+
+        db.set(&"big", &big[..], 0).unwrap();
+        assert_eq!(db.fetch(&"big").unwrap().unwrap().into_vec(), big);
+
+        let _ = remove_file(path);
     }
-    */
 
-    /*
     #[test]
-    fn test_values_cannot_escape() {
-        let db = MDBM::new(
-            &Path::new("test.db"),
-            super::MDBM_O_RDWR | super::MDBM_O_CREAT,
-            0o644,
-            0,
-            0,
-        )
-        .unwrap();
+    fn test_is_mdbm_file() {
+        let path = Path::new("test_is_mdbm_file.db");
+        let other = Path::new("test_is_mdbm_file.txt");
+        let _ = remove_file(path);
+        MDBM::new(path, Default::default(), 0o644, 0, 0).unwrap();
+        std::fs::write(other, vec![0x5au8; 8192]).unwrap();
 
-        let _ = {
-            db.set(&"hello", &"world", 0).unwrap();
+        assert!(MDBM::is_mdbm_file(path));
+        assert!(!MDBM::is_mdbm_file(other));
+        assert!(!MDBM::is_mdbm_file("test_is_mdbm_file_missing.db"));
+        assert!(!Path::new("test_is_mdbm_file_missing.db").exists());
 
-            let key = "hello";
-            let value = db.lock(&key, 0).unwrap();
-            str::from_utf8(value.get().unwrap()).unwrap()
-        };
+        let _ = remove_file(path);
+        let _ = remove_file(other);
     }
-    */
 
-    /*
     #[test]
-    fn test_values_cannot_escape_database() {
-        let _ = {
-            let db = MDBM::new(
-                &Path::new("test.db"),
-                super::MDBM_O_RDWR | super::MDBM_O_CREAT,
-                0o644,
-                0,
+    fn test_sample() {
+        let path = Path::new("test_sample.db");
+        let _ = remove_file(path);
+        let db = MDBM::new(path, Default::default(), 0o644, 0, 0).unwrap();
+        for i in 0..100 {
+            db.set(
+                format!("key{}", i).as_str(),
+                format!("value{}", i).as_str(),
                 0,
             )
             .unwrap();
+        }
 
-            db.set(&"hello", &"world", 0).unwrap();
+        let sample = db.sample(10).unwrap();
+        assert_eq!(sample.len(), 10);
+        let mut keys: Vec<&Vec<u8>> = sample.iter().map(|(k, _)| k).collect();
+        keys.sort();
+        keys.dedup();
+        assert_eq!(keys.len(), 10);
+        for (key, value) in &sample {
+            assert_eq!(db.fetch(&key[..]).unwrap().as_deref(), Some(&value[..]));
+        }
 
-            let key = "hello";
-            let value = db.lock(&key, 0).unwrap();
-            str::from_utf8(value.get().unwrap()).unwrap()
-        };
+        assert_eq!(db.sample(1000).unwrap().len(), 100);
+        assert!(db.sample(0).unwrap().is_empty());
+
+        let _ = remove_file(path);
+    }
+
+    #[test]
+    fn test_lock_unlock() {
+        use std::thread;
+
+        let path = "test_lock_unlock.db";
+        let _ = remove_file(path);
+        let db = MDBM::new(path, Default::default(), 0o644, 0, 0).unwrap();
+        db.set(&"key", &"value", 0).unwrap();
+
+        let key = "key";
+        let lock = db.lock(&key, super::WRITE_LOCK).unwrap();
+        assert_eq!(lock.get(), Some(&b"value"[..]));
+        assert!(lock.unlock().is_ok());
+
+        let other = thread::spawn(move || {
+            let db = MDBM::new(path, Default::default(), 0o644, 0, 0).unwrap();
+            let key = "key";
+            let lock = db.try_lock(&key, super::WRITE_LOCK).unwrap();
+            lock.is_some()
+        });
+        assert!(other.join().unwrap());
+
+        let _ = remove_file(path);
+    }
+
+    #[test]
+    fn test_store_compressed() {
+        use super::Compressor;
+
+        /// Run-length encoding as (count, byte) pairs.
+        struct RunLength;
+
+        impl Compressor for RunLength {
+            fn compress(&self, data: &[u8]) -> Vec<u8> {
+                let mut out = Vec::new();
+                for &b in data {
+                    let n = out.len();
+                    if n >= 2 && out[n - 1] == b && out[n - 2] < 255 {
+                        out[n - 2] += 1;
+                    } else {
+                        out.extend_from_slice(&[1, b]);
+                    }
+                }
+                out
+            }
+
+            fn decompress(&self, data: &[u8]) -> Result<Vec<u8>, io::Error> {
+                if !data.len().is_multiple_of(2) {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, "odd length"));
+                }
+                let mut out = Vec::new();
+                for pair in data.chunks(2) {
+                    out.extend(std::iter::repeat_n(pair[1], pair[0] as usize));
+                }
+                Ok(out)
+            }
+        }
+
+        let path = Path::new("test_store_compressed.db");
+        let _ = remove_file(path);
+        let db = MDBM::new(path, Default::default(), 0o644, 0, 0).unwrap();
+
+        let value = [vec![b'a'; 500], vec![b'b'; 300]].concat();
+        db.store_compressed(&"runs", &value[..], &RunLength)
+            .unwrap();
+        let stored = db.fetch(&"runs").unwrap().unwrap();
+        assert!(stored.len() < value.len(), "stored {} bytes", stored.len());
+        assert_eq!(
+            db.fetch_decompressed(&"runs", &RunLength).unwrap(),
+            Some(value)
+        );
+
+        // Values that don't shrink are stored as they are.
+        db.store_compressed(&"plain", &"abc", &RunLength).unwrap();
+        assert_eq!(db.fetch(&"plain").unwrap().as_deref(), Some(&b"\0abc"[..]));
+        assert_eq!(
+            db.fetch_decompressed(&"plain", &RunLength).unwrap(),
+            Some(b"abc".to_vec())
+        );
+
+        assert_eq!(db.fetch_decompressed(&"missing", &RunLength).unwrap(), None);
+        db.set(&"bare", &"", 0).unwrap();
+        let err = db.fetch_decompressed(&"bare", &RunLength).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        let _ = remove_file(path);
+    }
+
+    #[test]
+    fn test_fsync() {
+        let path = Path::new("test_fsync.db");
+        let _ = remove_file(path);
+        let db = MDBM::new(path, Default::default(), 0o644, 0, 0).unwrap();
+        db.set(&"durable", &"yes", 0).unwrap();
+        db.sync().unwrap();
+        db.fsync().unwrap();
+        db.close().unwrap();
+
+        let db = MDBM::new(path, Default::default(), 0o644, 0, 0).unwrap();
+        assert_eq!(db.fetch_str(&"durable").unwrap().as_deref(), Some("yes"));
+
+        let _ = remove_file(path);
     }
-    */
 }